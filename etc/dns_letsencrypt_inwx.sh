@@ -0,0 +1,44 @@
+#!/usr/bin/env sh
+
+# acme.sh dnsapi plugin for letsencrypt-inwx, generated by `letsencrypt-inwx acme-sh-plugin`.
+# Copy it to ~/.acme.sh/dnsapi/ and issue certificates with `acme.sh --issue --dns dns_letsencrypt_inwx -d example.com`.
+#
+# LETSENCRYPT_INWX_BIN: the path to the letsencrypt-inwx executable
+# LETSENCRYPT_INWX_CONFIG: optional, the path to the configfile
+
+dns_letsencrypt_inwx_add() {
+  fulldomain=$1
+  txtvalue=$2
+
+  _dns_letsencrypt_inwx_setup
+
+  _info "Adding TXT record for $fulldomain with letsencrypt-inwx"
+  _dns_letsencrypt_inwx add "$fulldomain" "$txtvalue"
+}
+
+dns_letsencrypt_inwx_rm() {
+  fulldomain=$1
+  txtvalue=$2
+
+  _dns_letsencrypt_inwx_setup
+
+  _info "Removing TXT record for $fulldomain with letsencrypt-inwx"
+  _dns_letsencrypt_inwx rm "$fulldomain" "$txtvalue"
+}
+
+_dns_letsencrypt_inwx_setup() {
+  LETSENCRYPT_INWX_BIN="${LETSENCRYPT_INWX_BIN:-$(_readaccountconf_mutable LETSENCRYPT_INWX_BIN)}"
+  LETSENCRYPT_INWX_BIN="${LETSENCRYPT_INWX_BIN:-{{BIN}}}"
+  LETSENCRYPT_INWX_CONFIG="${LETSENCRYPT_INWX_CONFIG:-$(_readaccountconf_mutable LETSENCRYPT_INWX_CONFIG)}"
+
+  _saveaccountconf_mutable LETSENCRYPT_INWX_BIN "$LETSENCRYPT_INWX_BIN"
+  _saveaccountconf_mutable LETSENCRYPT_INWX_CONFIG "$LETSENCRYPT_INWX_CONFIG"
+}
+
+_dns_letsencrypt_inwx() {
+  if [ -n "$LETSENCRYPT_INWX_CONFIG" ]; then
+    "$LETSENCRYPT_INWX_BIN" acme-sh -c "$LETSENCRYPT_INWX_CONFIG" "$@"
+  else
+    "$LETSENCRYPT_INWX_BIN" acme-sh "$@"
+  fi
+}
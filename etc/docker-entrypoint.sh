@@ -1,29 +1,9 @@
 #!/bin/sh
 
-CONF_CREATED=false
+# without a mounted config file letsencrypt-inwx uses the account of INWX_USER and INWX_PASSWORD
 
-if [ ! -z "$INWX_USER" -a ! -z "$INWX_PASSWD" ]; then
-    CONF_CREATED=true
-    >&2 echo "\
-!!! WARNING !!!
-PASSING INWX_USER AND INWX_PASSWD AS ENV VARIABLES IS DEPRECATED AND WILL BE REMOVED IN THE FUTURE!
-You should mount a config file into the container instead. See https://github.com/kegato/letsencrypt-inwx for details.
-"
-    cat << EOF > /etc/letsencrypt-inwx.json
-{
-    "accounts": [{
-        "username": "$INWX_USER",
-        "password": "$INWX_PASSWD"
-    }]
-}
-EOF
-    chmod 600 /etc/letsencrypt-inwx.json
-fi
+# a mounted config file is refused if it is accessible by group or others, restrict it with
+# chmod 600 on the host or set LETSENCRYPT_INWX_ALLOW_INSECURE_PERMISSIONS=1 to load it anyway
 
 set -x
-certbot -n --agree-tos $@
-set +x
-
-if [ $CONF_CREATED = true ]; then
-    rm /etc/letsencrypt-inwx.json
-fi
+exec certbot -n --agree-tos "$@"
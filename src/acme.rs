@@ -0,0 +1,484 @@
+use crate::config::Options;
+use crate::dns::remove_trailing_dot;
+use crate::http;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, ACCEPT, CONTENT_TYPE, LOCATION};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+const MAX_COMMON_NAME_LENGTH: usize = 64;
+
+#[derive(Debug)]
+pub enum AcmeError {
+    ConnectionError(reqwest::Error),
+    CryptoError(ErrorStack),
+    InvalidResponse,
+    ApiError { kind: String, detail: String },
+    ValidationFailed(String),
+    Timeout,
+}
+
+impl fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcmeError::ConnectionError(e) => {
+                write!(f, "Could not connect to the acme server: {}", e)
+            }
+            AcmeError::CryptoError(e) => write!(f, "A cryptographic operation failed: {}", e),
+            AcmeError::InvalidResponse => {
+                write!(f, "The acme server did not return a valid response")
+            }
+            AcmeError::ApiError { kind, detail } => write!(
+                f,
+                "The acme server did return an error: type={}, detail={}",
+                kind, detail
+            ),
+            AcmeError::ValidationFailed(domain) => {
+                write!(f, "The dns-01 challenge for {} failed", domain)
+            }
+            AcmeError::Timeout => write!(f, "The acme server did not finish in time"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for AcmeError {
+    fn from(e: reqwest::Error) -> AcmeError {
+        AcmeError::ConnectionError(e)
+    }
+}
+
+impl From<ErrorStack> for AcmeError {
+    fn from(e: ErrorStack) -> AcmeError {
+        AcmeError::CryptoError(e)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Order {
+    #[serde(skip)]
+    pub url: String,
+    pub status: String,
+    #[serde(default)]
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    identifier: Identifier,
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+/// A pending dns-01 challenge: `value` has to be published as a TXT record at `name`.
+#[derive(Debug, Clone)]
+pub struct DnsChallenge {
+    pub domain: String,
+    pub name: String,
+    pub value: String,
+    url: String,
+    authorization: String,
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// Returns the name of the TXT record for the dns-01 challenge of the domain.
+pub fn challenge_name(domain: &str) -> String {
+    let domain = remove_trailing_dot(domain);
+
+    format!("_acme-challenge.{}", domain.trim_start_matches("*."))
+}
+
+/// Returns the value of the TXT record for the dns-01 challenge with the key authorization.
+pub fn challenge_value(key_authorization: &str) -> Result<String, ErrorStack> {
+    Ok(b64(&hash(
+        MessageDigest::sha256(),
+        key_authorization.as_bytes(),
+    )?))
+}
+
+pub struct AcmeClient {
+    client: Client,
+    directory: Directory,
+    key: PKey<Private>,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    pub fn new(
+        directory_url: &str,
+        key: PKey<Private>,
+        options: &Options,
+    ) -> Result<AcmeClient, AcmeError> {
+        let mut builder = Client::builder();
+
+        if let Some(proxies) = http::proxies(options.proxy.as_deref(), options.no_proxy.as_deref())?
+        {
+            builder = builder.no_proxy();
+
+            for proxy in proxies {
+                builder = builder.proxy(proxy);
+            }
+        }
+
+        let client = builder.build()?;
+
+        debug!("Fetching acme directory {}", directory_url);
+
+        let directory = client
+            .get(directory_url)
+            .send()?
+            .json()
+            .map_err(|_| AcmeError::InvalidResponse)?;
+
+        Ok(AcmeClient {
+            client,
+            directory,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn jwk(&self) -> Result<Value, AcmeError> {
+        let ec_key = self.key.ec_key()?;
+        let mut ctx = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec_key
+            .public_key()
+            .affine_coordinates(ec_key.group(), &mut x, &mut y, &mut ctx)?;
+
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&x.to_vec_padded(32)?),
+            "y": b64(&y.to_vec_padded(32)?),
+        }))
+    }
+
+    fn thumbprint(&self) -> Result<String, AcmeError> {
+        // serde_json sorts object keys, which is exactly the form RFC 7638 requires
+        let jwk = self.jwk()?.to_string();
+        Ok(b64(&hash(MessageDigest::sha256(), jwk.as_bytes())?))
+    }
+
+    fn update_nonce(&mut self, headers: &HeaderMap) {
+        if let Some(nonce) = headers
+            .get("Replay-Nonce")
+            .and_then(|value| value.to_str().ok())
+        {
+            self.nonce = Some(nonce.to_owned());
+        }
+    }
+
+    fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+
+        let response = self.client.head(&self.directory.new_nonce).send()?;
+        self.update_nonce(response.headers());
+
+        self.nonce.take().ok_or(AcmeError::InvalidResponse)
+    }
+
+    fn sign(&mut self, url: &str, payload: Option<&Value>) -> Result<String, AcmeError> {
+        let mut protected = json!({
+            "alg": "ES256",
+            "nonce": self.nonce()?,
+            "url": url,
+        });
+
+        match self.kid {
+            Some(ref kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk()?,
+        }
+
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = match payload {
+            Some(payload) => b64(payload.to_string().as_bytes()),
+            None => String::new(),
+        };
+
+        let digest = hash(
+            MessageDigest::sha256(),
+            format!("{}.{}", protected, payload).as_bytes(),
+        )?;
+        let ec_key = self.key.ec_key()?;
+        let signature = EcdsaSig::sign(&digest, &ec_key)?;
+        let mut raw_signature = signature.r().to_vec_padded(32)?;
+        raw_signature.extend(signature.s().to_vec_padded(32)?);
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(&raw_signature),
+        })
+        .to_string())
+    }
+
+    /// Sends a signed request, `None` as payload results in a POST-as-GET request.
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, AcmeError> {
+        let mut retried = false;
+
+        loop {
+            let body = self.sign(url, payload)?;
+
+            trace!("Sending acme request to {}", url);
+
+            let response = self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(body)
+                .send()?;
+
+            self.update_nonce(response.headers());
+
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let problem: Problem = response.json().map_err(|_| AcmeError::InvalidResponse)?;
+
+            if problem.kind == BAD_NONCE && !retried {
+                debug!("The acme server rejected the nonce, retrying");
+                retried = true;
+                continue;
+            }
+
+            return Err(AcmeError::ApiError {
+                kind: problem.kind,
+                detail: problem.detail,
+            });
+        }
+    }
+
+    pub fn register(&mut self, email: Option<&str>) -> Result<(), AcmeError> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url, Some(&payload))?;
+
+        let kid = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(AcmeError::InvalidResponse)?;
+
+        debug!("Using acme account {}", kid);
+        self.kid = Some(kid.to_owned());
+
+        Ok(())
+    }
+
+    pub fn new_order(&mut self, domains: &[String]) -> Result<Order, AcmeError> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+
+        let url = self.directory.new_order.clone();
+        let response = self.post(&url, Some(&json!({ "identifiers": identifiers })))?;
+
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+            .ok_or(AcmeError::InvalidResponse)?;
+
+        let mut order: Order = response.json().map_err(|_| AcmeError::InvalidResponse)?;
+        order.url = location;
+
+        Ok(order)
+    }
+
+    fn get_order(&mut self, url: &str) -> Result<Order, AcmeError> {
+        let mut order: Order = self
+            .post(url, None)?
+            .json()
+            .map_err(|_| AcmeError::InvalidResponse)?;
+        order.url = url.to_owned();
+
+        Ok(order)
+    }
+
+    fn get_authorization(&mut self, url: &str) -> Result<Authorization, AcmeError> {
+        self.post(url, None)?
+            .json()
+            .map_err(|_| AcmeError::InvalidResponse)
+    }
+
+    /// Returns the dns-01 challenges of all authorizations of the order which are not valid yet.
+    pub fn dns_challenges(&mut self, order: &Order) -> Result<Vec<DnsChallenge>, AcmeError> {
+        let thumbprint = self.thumbprint()?;
+        let mut challenges = Vec::new();
+
+        for url in &order.authorizations {
+            let authorization = self.get_authorization(url)?;
+
+            if authorization.status == "valid" {
+                debug!("{} is already authorized", authorization.identifier.value);
+                continue;
+            }
+
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.kind == "dns-01")
+                .ok_or_else(|| {
+                    AcmeError::ValidationFailed(authorization.identifier.value.to_owned())
+                })?;
+
+            let key_authorization = format!("{}.{}", challenge.token, thumbprint);
+
+            challenges.push(DnsChallenge {
+                domain: authorization.identifier.value.to_owned(),
+                name: challenge_name(&authorization.identifier.value),
+                value: challenge_value(&key_authorization)?,
+                url: challenge.url.to_owned(),
+                authorization: url.to_owned(),
+            });
+        }
+
+        Ok(challenges)
+    }
+
+    /// Tells the acme server that the challenge is ready and waits until it has been validated.
+    pub fn validate(&mut self, challenge: &DnsChallenge) -> Result<(), AcmeError> {
+        self.post(&challenge.url, Some(&json!({})))?;
+
+        for _ in 0..60 {
+            let authorization = self.get_authorization(&challenge.authorization)?;
+
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => sleep(Duration::from_secs(2)),
+                _ => return Err(AcmeError::ValidationFailed(challenge.domain.to_owned())),
+            }
+        }
+
+        Err(AcmeError::Timeout)
+    }
+
+    /// Submits a csr for the private key and returns the issued certificate chain as pem.
+    pub fn finalize(
+        &mut self,
+        order: &Order,
+        domains: &[String],
+        key: &PKey<Private>,
+    ) -> Result<String, AcmeError> {
+        let mut csr = X509ReqBuilder::new()?;
+
+        // the common name is limited to 64 bytes, the domains are part of the SAN extension anyway
+        if let Some(domain) = domains
+            .first()
+            .filter(|domain| domain.len() <= MAX_COMMON_NAME_LENGTH)
+        {
+            let mut name = X509NameBuilder::new()?;
+            name.append_entry_by_text("CN", domain)?;
+            csr.set_subject_name(&name.build())?;
+        }
+
+        csr.set_pubkey(key)?;
+
+        let mut san = SubjectAlternativeName::new();
+        for domain in domains {
+            san.dns(domain);
+        }
+        let mut extensions = Stack::new()?;
+        extensions.push(san.build(&csr.x509v3_context(None))?)?;
+        csr.add_extensions(&extensions)?;
+        csr.sign(key, MessageDigest::sha256())?;
+
+        let csr = b64(&csr.build().to_der()?);
+        self.post(&order.finalize, Some(&json!({ "csr": csr })))?;
+
+        for _ in 0..60 {
+            let order = self.get_order(&order.url)?;
+
+            match (order.status.as_str(), order.certificate) {
+                ("valid", Some(certificate)) => return self.download(&certificate),
+                ("pending", _) | ("ready", _) | ("processing", _) | ("valid", None) => {
+                    sleep(Duration::from_secs(2))
+                }
+                _ => return Err(AcmeError::InvalidResponse),
+            }
+        }
+
+        Err(AcmeError::Timeout)
+    }
+
+    fn download(&mut self, url: &str) -> Result<String, AcmeError> {
+        let body = self.sign(url, None)?;
+
+        let response = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/jose+json")
+            .header(ACCEPT, "application/pem-certificate-chain")
+            .body(body)
+            .send()?;
+
+        self.update_nonce(response.headers());
+
+        if !response.status().is_success() {
+            return Err(AcmeError::InvalidResponse);
+        }
+
+        Ok(response.text()?)
+    }
+}
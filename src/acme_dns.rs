@@ -0,0 +1,405 @@
+use crate::auth::{audit, Client};
+use crate::challenge::{create_record, delete, Sessions};
+use crate::config::Config;
+use crate::encryption::write_private;
+use crate::http::{bind, header, in_network, path, read_json, respond_empty, respond_json};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tiny_http::{Method, Request, Server};
+
+// acme-dns keeps the two most recent values, so a certificate for a domain and its wildcard
+// can be validated at the same time
+const MAX_TXT_RECORDS: usize = 2;
+const PASSWORD_ITERATIONS: usize = 10000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Registration {
+    username: String,
+    password_hash: String,
+    salt: String,
+    subdomain: String,
+    #[serde(default)]
+    allowfrom: Vec<String>,
+    #[serde(default)]
+    txt: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct RegisterRequest {
+    #[serde(default)]
+    allowfrom: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct UpdateRequest {
+    subdomain: String,
+    txt: String,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rand_bytes(&mut bytes).expect("Failed to generate random bytes");
+    bytes
+}
+
+fn random_uuid() -> String {
+    let mut bytes = random_bytes(16);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = to_hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn hash_password(password: &str, salt: &str) -> String {
+    let mut hash = [0; 32];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        salt.as_bytes(),
+        PASSWORD_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut hash,
+    )
+    .expect("Failed to hash password");
+
+    to_hex(&hash)
+}
+
+fn load_registrations(path: &str) -> Result<Vec<Registration>, ()> {
+    match fs::read(path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| error!("Failed to parse registrations file: {}", e)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => {
+            error!("Failed to read registrations file: {}", e);
+            Err(())
+        }
+    }
+}
+
+/// Only the owner may read the file, as it contains the password hashes of the clients.
+fn save_registrations(path: &str, registrations: &[Registration]) -> Result<(), ()> {
+    let json = serde_json::to_vec_pretty(registrations).unwrap();
+
+    write_private(Path::new(path), &json)
+        .map_err(|e| error!("Failed to write registrations file: {}", e))
+}
+
+struct AcmeDnsServer<'a> {
+    config: &'a Config,
+    sessions: Sessions,
+    registrations: Vec<Registration>,
+}
+
+impl<'a> AcmeDnsServer<'a> {
+    fn register(&mut self, mut request: Request) {
+        if self.config.acme_dns.disable_registration {
+            return respond_json(request, 403, &json!({ "error": "registration_disabled" }));
+        }
+
+        let body: RegisterRequest = match request.body_length() {
+            Some(0) => RegisterRequest::default(),
+            _ => match read_json(&mut request) {
+                Some(body) => body,
+                None => return respond_json(request, 400, &json!({ "error": "malformed_json" })),
+            },
+        };
+
+        let any_ip = "0.0.0.0".parse().unwrap();
+        if body
+            .allowfrom
+            .iter()
+            .any(|network| in_network(any_ip, network).is_none())
+        {
+            return respond_json(request, 400, &json!({ "error": "invalid_allowfrom_cidr" }));
+        }
+
+        let password = base64::encode_config(&random_bytes(30), base64::URL_SAFE_NO_PAD);
+        let salt = to_hex(&random_bytes(16));
+        let registration = Registration {
+            username: random_uuid(),
+            password_hash: hash_password(&password, &salt),
+            salt,
+            subdomain: random_uuid(),
+            allowfrom: body.allowfrom,
+            txt: vec![],
+        };
+
+        self.registrations.push(registration.clone());
+
+        if save_registrations(&self.config.acme_dns.registrations, &self.registrations).is_err() {
+            self.registrations.pop();
+            return respond_json(request, 500, &json!({ "error": "storage_error" }));
+        }
+
+        info!("Registered subdomain {}", registration.subdomain);
+
+        respond_json(
+            request,
+            201,
+            &json!({
+                "username": registration.username,
+                "password": password,
+                "fulldomain": format!("{}.{}", registration.subdomain, self.config.acme_dns.domain),
+                "subdomain": registration.subdomain,
+                "allowfrom": registration.allowfrom,
+            }),
+        );
+    }
+
+    fn authenticate(&self, request: &Request) -> Option<usize> {
+        let username = header(request, "X-Api-User")?;
+        let password = header(request, "X-Api-Key")?;
+
+        let position = self
+            .registrations
+            .iter()
+            .position(|registration| registration.username == username)?;
+        let registration = &self.registrations[position];
+
+        let hash = hash_password(password, &registration.salt);
+        if !memcmp::eq(hash.as_bytes(), registration.password_hash.as_bytes()) {
+            return None;
+        }
+
+        if !registration.allowfrom.is_empty() {
+            let ip = request.remote_addr()?.ip();
+
+            if !registration
+                .allowfrom
+                .iter()
+                .any(|network| in_network(ip, network) == Some(true))
+            {
+                return None;
+            }
+        }
+
+        Some(position)
+    }
+
+    fn update(&mut self, mut request: Request) {
+        let position = match self.authenticate(&request) {
+            Some(position) => position,
+            None => return respond_json(request, 401, &json!({ "error": "forbidden" })),
+        };
+
+        let body: UpdateRequest = match read_json(&mut request) {
+            Some(body) => body,
+            None => return respond_json(request, 400, &json!({ "error": "malformed_json" })),
+        };
+
+        let registration = &self.registrations[position];
+        let name = format!("{}.{}", body.subdomain, self.config.acme_dns.domain);
+        let allowed = body.subdomain == registration.subdomain;
+
+        audit(
+            self.config,
+            &Client::Unrestricted(&registration.username),
+            "update",
+            &name,
+            "TXT",
+            allowed,
+        );
+
+        if !allowed {
+            return respond_json(request, 401, &json!({ "error": "forbidden" }));
+        }
+
+        if body.txt.len() != 43 {
+            return respond_json(request, 400, &json!({ "error": "bad_txt" }));
+        }
+
+        if create_record(self.config, &mut self.sessions, &name, &body.txt).is_err() {
+            return respond_json(request, 500, &json!({ "error": "update_failed" }));
+        }
+
+        let registration = &mut self.registrations[position];
+        registration.txt.push(body.txt.to_owned());
+
+        while registration.txt.len() > MAX_TXT_RECORDS {
+            let old_value = registration.txt.remove(0);
+
+            // a stale record does not prevent the validation, so this is only logged
+            let _ = delete(self.config, &mut self.sessions, &name, Some(&old_value));
+        }
+
+        // the record already exists, so a failed save is only logged
+        let _ = save_registrations(&self.config.acme_dns.registrations, &self.registrations);
+
+        respond_json(request, 200, &json!({ "txt": body.txt }));
+    }
+}
+
+/// Serves the acme-dns api, the TXT records are created at `<subdomain>.<acme_dns.domain>`
+/// which has to be hosted by inwx.
+pub fn serve(config: &Config) -> Result<(), ()> {
+    if config.acme_dns.domain.is_empty() {
+        error!("acme_dns.domain is not configured");
+        return Err(());
+    }
+
+    let registrations = load_registrations(&config.acme_dns.registrations)?;
+    let listener = bind(&config.acme_dns.listen)?;
+
+    handle_requests(config, registrations, &listener);
+
+    Ok(())
+}
+
+fn handle_requests(config: &Config, registrations: Vec<Registration>, listener: &Server) {
+    let mut server = AcmeDnsServer {
+        config,
+        sessions: Sessions::new(),
+        registrations,
+    };
+
+    for request in listener.incoming_requests() {
+        debug!("{} {}", request.method(), request.url());
+
+        match (request.method(), path(&request)) {
+            (Method::Post, "/register") => server.register(request),
+            (Method::Post, "/update") => server.update(request),
+            (Method::Get, "/health") => respond_empty(request, 200),
+            _ => respond_empty(request, 404),
+        }
+    }
+
+    server.sessions.logout();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, client, spawn_server, TempDir};
+    use reqwest::blocking::Client;
+    use serde_json::Value;
+    use std::os::unix::fs::PermissionsExt;
+
+    const TXT: &str = "0123456789012345678901234567890123456789012";
+
+    /// Starts the server with the records in a file of a new directory.
+    fn start(name: &str) -> (String, TempDir) {
+        let directory = TempDir::new(&format!("acme-dns-{}", name));
+
+        let config = test_util::config(json!({
+            "acme_dns": {
+                "domain": "acme.example.com",
+                "registrations": directory.join("registrations.json"),
+            },
+            "records_file": {
+                "path": directory.join("records.json"),
+                "zones": ["example.com"],
+            },
+        }));
+
+        let url = spawn_server(move |server| handle_requests(config, vec![], &server));
+
+        (url, directory)
+    }
+
+    fn records(directory: &TempDir) -> Vec<Value> {
+        serde_json::from_slice(&fs::read(directory.join("records.json")).unwrap()).unwrap()
+    }
+
+    fn update(client: &Client, url: &str, registration: &Value, txt: &str) -> u16 {
+        client
+            .post(format!("{}/update", url))
+            .header("X-Api-User", registration["username"].as_str().unwrap())
+            .header("X-Api-Key", registration["password"].as_str().unwrap())
+            .json(&json!({ "subdomain": registration["subdomain"], "txt": txt }))
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+    }
+
+    #[test]
+    fn register_and_update() {
+        let (url, directory) = start("update");
+        let client = client();
+
+        let response = client
+            .post(format!("{}/register", url))
+            .json(&json!({}))
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 201);
+        let registration: Value = response.json().unwrap();
+
+        let mode = fs::metadata(directory.join("registrations.json"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert_eq!(update(&client, &url, &registration, TXT), 200);
+
+        let created = records(&directory);
+        assert_eq!(created.len(), 1);
+        assert_eq!(
+            created[0]["name"],
+            format!(
+                "{}.acme.example.com",
+                registration["subdomain"].as_str().unwrap()
+            )
+        );
+        assert_eq!(created[0]["type"], "TXT");
+        assert_eq!(created[0]["content"], TXT);
+
+        // only the two most recent values are kept
+        let second = TXT.replace('0', "a");
+        let third = TXT.replace('0', "b");
+        assert_eq!(update(&client, &url, &registration, &second), 200);
+        assert_eq!(update(&client, &url, &registration, &third), 200);
+
+        let contents: Vec<Value> = records(&directory)
+            .iter()
+            .map(|record| record["content"].clone())
+            .collect();
+        assert_eq!(contents, vec![json!(second), json!(third)]);
+    }
+
+    #[test]
+    fn rejects_invalid_updates() {
+        let (url, directory) = start("invalid");
+        let client = client();
+
+        let registration: Value = client
+            .post(format!("{}/register", url))
+            .json(&json!({ "allowfrom": ["127.0.0.1/32"] }))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+
+        let mut wrong_password = registration.clone();
+        wrong_password["password"] = json!("wrong");
+        assert_eq!(update(&client, &url, &wrong_password, TXT), 401);
+
+        let mut other_subdomain = registration.clone();
+        other_subdomain["subdomain"] = json!("other");
+        assert_eq!(update(&client, &url, &other_subdomain, TXT), 401);
+
+        assert_eq!(update(&client, &url, &registration, "short"), 400);
+
+        assert!(!directory.join("records.json").exists());
+    }
+}
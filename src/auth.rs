@@ -0,0 +1,247 @@
+use crate::config::{ApiToken, Config};
+use crate::http::header;
+use openssl::memcmp;
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::Request;
+
+/// The identity a change is made with, clients without a token may change every record.
+pub enum Client<'a> {
+    Unrestricted(&'a str),
+    Token(&'a ApiToken),
+}
+
+impl<'a> Client<'a> {
+    pub fn name(&self) -> &str {
+        match self {
+            Client::Unrestricted(name) => name,
+            Client::Token(token) => &token.name,
+        }
+    }
+}
+
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+/// Checks a basic auth header against the user and password.
+pub fn basic_auth_matches(request: &Request, username: &str, password: &str) -> bool {
+    basic_auth(request)
+        .map(|(user, pass)| {
+            secure_eq(user.as_bytes(), username.as_bytes())
+                & secure_eq(pass.as_bytes(), password.as_bytes())
+        })
+        .unwrap_or(false)
+}
+
+fn basic_auth(request: &Request) -> Option<(String, String)> {
+    let credentials = header(request, "Authorization")?.strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+    let mut parts = credentials.splitn(2, ':');
+
+    Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+}
+
+/// Returns the token of the request, either sent as bearer token or as basic auth user and password.
+pub fn authenticate<'a>(config: &'a Config, request: &Request) -> Option<&'a ApiToken> {
+    if let Some(secret) =
+        header(request, "Authorization").and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return find_token_by_secret(config, secret.trim());
+    }
+
+    let (username, password) = basic_auth(request)?;
+
+    config.tokens.iter().find(|token| {
+        token.name == username && secure_eq(token.secret.expose().as_bytes(), password.as_bytes())
+    })
+}
+
+pub fn find_token_by_secret<'a>(config: &'a Config, secret: &str) -> Option<&'a ApiToken> {
+    config
+        .tokens
+        .iter()
+        .find(|token| secure_eq(token.secret.expose().as_bytes(), secret.as_bytes()))
+}
+
+pub fn find_token<'a>(config: &'a Config, name: &str) -> Option<&'a ApiToken> {
+    config.tokens.iter().find(|token| token.name == name)
+}
+
+/// Matches a name against a pattern, `*` matches any sequence of characters (including dots).
+fn matches_pattern(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_pattern(rest, &name[skip..])),
+        Some((c, rest)) => match name.split_first() {
+            Some((n, name)) => c.eq_ignore_ascii_case(n) && matches_pattern(rest, name),
+            None => false,
+        },
+    }
+}
+
+fn is_allowed(token: &ApiToken, name: &str, record_type: &str) -> bool {
+    let name = name.trim_end_matches('.');
+
+    token
+        .record_types
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(record_type))
+        && token.names.iter().any(|pattern| {
+            matches_pattern(pattern.trim_end_matches('.').as_bytes(), name.as_bytes())
+        })
+}
+
+/// Checks whether the pattern matches the zone or any name below it.
+fn matches_zone(pattern: &[u8], zone: &[u8]) -> bool {
+    let mut suffix = b".".to_vec();
+    suffix.extend_from_slice(zone);
+
+    // the names below the zone end with the suffix, the part of the pattern matching the rest
+    // of the name matches some string in any case
+    matches_pattern(pattern, zone)
+        || (0..=pattern.len()).any(|start| matches_pattern(&pattern[start..], &suffix))
+}
+
+/// Checks whether the client may see the record, reads are not written to the audit log.
+pub fn may_read(client: &Client, name: &str, record_type: &str) -> bool {
+    match client {
+        Client::Unrestricted(_) => true,
+        Client::Token(token) => is_allowed(token, name, record_type),
+    }
+}
+
+/// Checks whether the client may see any record of the zone.
+pub fn may_read_zone(client: &Client, zone: &str) -> bool {
+    let zone = zone.trim_end_matches('.');
+
+    match client {
+        Client::Unrestricted(_) => true,
+        Client::Token(token) => token
+            .names
+            .iter()
+            .any(|pattern| matches_zone(pattern.trim_end_matches('.').as_bytes(), zone.as_bytes())),
+    }
+}
+
+/// Writes a change made with the client to the audit log, if one is configured.
+pub fn audit(
+    config: &Config,
+    client: &Client,
+    action: &str,
+    name: &str,
+    record_type: &str,
+    allowed: bool,
+) {
+    let path = match config.options.audit_log {
+        Some(ref path) => path,
+        None => return,
+    };
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    let entry = json!({
+        "time": time,
+        "client": client.name(),
+        "action": action,
+        "name": name,
+        "record_type": record_type,
+        "allowed": allowed,
+    });
+
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", entry));
+
+    if let Err(e) = result {
+        error!("Failed to write audit log: {}", e);
+    }
+}
+
+/// Checks whether the client may change the record and writes the decision to the audit log.
+pub fn authorize(
+    config: &Config,
+    client: &Client,
+    action: &str,
+    name: &str,
+    record_type: &str,
+) -> bool {
+    let allowed = match client {
+        Client::Unrestricted(_) => true,
+        Client::Token(token) => is_allowed(token, name, record_type),
+    };
+
+    match allowed {
+        true => info!(
+            "{} may {} {} record {}",
+            client.name(),
+            action,
+            record_type,
+            name
+        ),
+        false => warn!(
+            "{} may not {} {} record {}",
+            client.name(),
+            action,
+            record_type,
+            name
+        ),
+    }
+
+    audit(config, client, action, name, record_type, allowed);
+
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Secret;
+
+    fn token(names: &[&str]) -> ApiToken {
+        ApiToken {
+            name: "test".to_owned(),
+            secret: Secret::new("secret".to_owned()),
+            names: names.iter().map(|name| name.to_string()).collect(),
+            record_types: vec!["TXT".to_owned()],
+        }
+    }
+
+    #[test]
+    fn read_scope() {
+        let token = token(&["_acme-challenge.*.shop.example.com"]);
+        let client = Client::Token(&token);
+
+        assert!(may_read_zone(&client, "example.com"));
+        assert!(may_read_zone(&client, "shop.example.com."));
+        assert!(!may_read_zone(&client, "example.org"));
+        assert!(!may_read_zone(&client, "other.example.com"));
+
+        assert!(may_read(
+            &client,
+            "_acme-challenge.www.shop.example.com",
+            "txt"
+        ));
+        assert!(!may_read(
+            &client,
+            "_acme-challenge.www.shop.example.com",
+            "A"
+        ));
+        assert!(!may_read(&client, "www.shop.example.com", "TXT"));
+        assert!(may_read(&Client::Unrestricted("test"), "example.org", "A"));
+    }
+
+    #[test]
+    fn wildcard_zone_scope() {
+        let token = token(&["*"]);
+
+        assert!(may_read_zone(&Client::Token(&token), "example.org"));
+    }
+}
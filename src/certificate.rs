@@ -0,0 +1,178 @@
+use crate::config::{Certificate, KeyType};
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::X509;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum CertificateError {
+    IoError(io::Error),
+    CryptoError(ErrorStack),
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertificateError::IoError(e) => {
+                write!(f, "Could not access a key or certificate: {}", e)
+            }
+            CertificateError::CryptoError(e) => write!(f, "Invalid key or certificate: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for CertificateError {
+    fn from(e: io::Error) -> CertificateError {
+        CertificateError::IoError(e)
+    }
+}
+
+impl From<ErrorStack> for CertificateError {
+    fn from(e: ErrorStack) -> CertificateError {
+        CertificateError::CryptoError(e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CertificateStatus {
+    Missing,
+    DomainsChanged,
+    Expiring(i32),
+    Valid(i32),
+}
+
+impl CertificateStatus {
+    pub fn needs_renewal(&self) -> bool {
+        !matches!(self, CertificateStatus::Valid(_))
+    }
+}
+
+impl fmt::Display for CertificateStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertificateStatus::Missing => write!(f, "has not been issued yet"),
+            CertificateStatus::DomainsChanged => write!(f, "does not match the configured domains"),
+            CertificateStatus::Expiring(days) => write!(f, "expires in {} days", days),
+            CertificateStatus::Valid(days) => write!(f, "is valid for {} more days", days),
+        }
+    }
+}
+
+/// Compares the certificate on disk with the configured one.
+pub fn check_certificate(
+    certificate: &Certificate,
+    renew_before_days: u32,
+) -> Result<CertificateStatus, CertificateError> {
+    let pem = match fs::read(&certificate.cert_path) {
+        Ok(pem) => pem,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(CertificateStatus::Missing),
+        Err(e) => return Err(e.into()),
+    };
+
+    let cert = X509::from_pem(&pem)?;
+
+    let issued: BTreeSet<String> = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname().map(|name| name.to_lowercase()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let configured: BTreeSet<String> = certificate
+        .domains
+        .iter()
+        .map(|domain| domain.to_lowercase())
+        .collect();
+
+    if issued != configured {
+        return Ok(CertificateStatus::DomainsChanged);
+    }
+
+    let days_left = Asn1Time::days_from_now(0)?.diff(cert.not_after())?.days;
+
+    if days_left < renew_before_days as i32 {
+        Ok(CertificateStatus::Expiring(days_left))
+    } else {
+        Ok(CertificateStatus::Valid(days_left))
+    }
+}
+
+pub fn generate_key(key_type: KeyType) -> Result<PKey<Private>, ErrorStack> {
+    match key_type {
+        KeyType::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?),
+        KeyType::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?),
+        KeyType::Ec256 => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+            PKey::from_ec_key(EcKey::generate(&group)?)
+        }
+        KeyType::Ec384 => {
+            let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+            PKey::from_ec_key(EcKey::generate(&group)?)
+        }
+    }
+}
+
+/// Writes the file through a temporary file so readers never see a partially written key.
+fn write_file(path: &str, contents: &[u8], private: bool) -> io::Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = format!("{}.tmp", path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if private { 0o600 } else { 0o644 });
+    }
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+/// Loads the acme account key or generates and stores a new one if it does not exist yet.
+pub fn load_account_key(path: &str) -> Result<PKey<Private>, CertificateError> {
+    match fs::read(path) {
+        Ok(pem) => Ok(PKey::private_key_from_pem(&pem)?),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("Generating a new acme account key at {}", path);
+
+            let key = generate_key(KeyType::Ec256)?;
+            write_file(path, &key.private_key_to_pem_pkcs8()?, true)?;
+
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write_certificate(
+    certificate: &Certificate,
+    key: &PKey<Private>,
+    chain: &str,
+) -> Result<(), CertificateError> {
+    write_file(
+        &certificate.key_path,
+        &key.private_key_to_pem_pkcs8()?,
+        true,
+    )?;
+    write_file(&certificate.cert_path, chain.as_bytes(), false)?;
+
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use crate::config::{Account, Config, Options};
+use crate::dns::check_txt_record;
+use crate::inwx::{Inwx, InwxError};
+use crate::provider::{DnsProvider, FileProvider, ProviderError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Keeps one logged in api session per account, so that several operations share a single login.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Vec<Inwx>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions { sessions: vec![] }
+    }
+
+    fn get(&mut self, config: &Config, account: &Account) -> Result<&mut Inwx, InwxError> {
+        let position = self.sessions.iter().position(|api| {
+            api.account().username == account.username
+                && api.account().ote == account.ote
+                && api.account().api_url == account.api_url
+        });
+
+        match position {
+            Some(position) => Ok(&mut self.sessions[position]),
+            None => {
+                // the proxy and no_proxy of the account override the ones of the options
+                // independently, Inwx resolves them against the environment
+                let mut account = account.clone();
+                account.proxy = account.proxy.or_else(|| config.options.proxy.clone());
+                account.no_proxy = account.no_proxy.or_else(|| config.options.no_proxy.clone());
+
+                self.sessions.push(Inwx::new(&account)?);
+                Ok(self.sessions.last_mut().unwrap())
+            }
+        }
+    }
+
+    pub fn logout(&mut self) {
+        for api in self.sessions.drain(..) {
+            if let Err(e) = api.logout() {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Runs `op` with the provider responsible for the domain, returns whether the records stay out
+/// of public dns (because the account uses the ote or the records file is configured).
+pub fn execute_api_commands<F>(
+    config: &Config,
+    sessions: &mut Sessions,
+    domain: &str,
+    mut op: F,
+) -> Result<bool, ()>
+where
+    F: FnMut(&mut dyn DnsProvider) -> Result<(), ProviderError>,
+{
+    if let Some(ref records_file) = config.records_file {
+        return match op(&mut FileProvider::new(records_file)) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                error!("{}", e);
+                Err(())
+            }
+        };
+    }
+
+    if config.accounts.is_empty() {
+        error!("No accounts configured");
+        return Err(());
+    }
+
+    let mut filtered_accounts = Vec::new();
+
+    match config.account_for(domain) {
+        Some(account) => {
+            info!("Using account {}", account.username);
+            filtered_accounts.push(account);
+        }
+        None => {
+            warn!(
+                "Domain not configured: Trying {} configured accounts",
+                config.accounts.len()
+            );
+            filtered_accounts.extend(config.accounts.iter());
+        }
+    };
+
+    for account in filtered_accounts {
+        let api = sessions.get(config, account).map_err(|e| error!("{}", e))?;
+
+        match op(api) {
+            Err(ProviderError::DomainNotFound) => {}
+            Err(e) => {
+                error!("{}", e);
+                return Err(());
+            }
+            _ => {
+                return Ok(account.ote);
+            }
+        }
+    }
+
+    error!("{}", ProviderError::DomainNotFound);
+    Err(())
+}
+
+/// Returns the zones of all accounts.
+pub fn list_zones(config: &Config, sessions: &mut Sessions) -> Result<Vec<String>, ()> {
+    if let Some(ref records_file) = config.records_file {
+        return FileProvider::new(records_file)
+            .list_zones()
+            .map_err(|e| error!("{}", e));
+    }
+
+    let mut zones = Vec::new();
+
+    for account in &config.accounts {
+        let api = sessions.get(config, account).map_err(|e| error!("{}", e))?;
+        zones.extend(api.list_zones().map_err(|e| error!("{}", e))?);
+    }
+
+    zones.sort();
+    zones.dedup();
+
+    Ok(zones)
+}
+
+pub fn create_record(
+    config: &Config,
+    sessions: &mut Sessions,
+    domain: &str,
+    value: &str,
+) -> Result<bool, ()> {
+    info!("Creating TXT record...");
+
+    let is_ote = execute_api_commands(config, sessions, domain, |api| {
+        api.create_txt_record(domain, value)?;
+        Ok(())
+    })?;
+
+    info!("=> done!");
+
+    Ok(is_ote)
+}
+
+/// Waits until the records are publicly visible, every record is checked with the options of
+/// its domain.
+pub fn wait_for_propagation(config: &Config, records: &[(String, String)]) -> Result<(), ()> {
+    let mut pending: Vec<(&str, &str, Options)> = records
+        .iter()
+        .map(|(domain, value)| (domain.as_str(), value.as_str(), config.options_for(domain)))
+        .filter(|(_, _, options)| !options.no_dns_check)
+        .collect();
+
+    if !pending.is_empty() {
+        info!("Waiting for the dns record to be publicly visible...");
+
+        let start = Instant::now();
+        let mut wait_secs = 5;
+
+        loop {
+            // timeout after 10 minutes
+            if start.elapsed() > Duration::from_secs(60 * 10) {
+                error!("=> timeout!");
+                return Err(());
+            }
+
+            pending.retain(|(domain, value, options)| {
+                !check_txt_record(&options.dns_server, domain, value)
+            });
+
+            if pending.is_empty() {
+                break;
+            }
+
+            wait_secs *= 2;
+
+            sleep(Duration::from_secs(wait_secs));
+        }
+
+        info!("=> done!");
+    }
+
+    // the longest interval of the domains applies to the whole batch
+    let wait_interval = records
+        .iter()
+        .map(|(domain, _)| config.options_for(domain).wait_interval)
+        .max()
+        .unwrap_or(config.options.wait_interval);
+
+    if wait_interval > 0 {
+        info!("Waiting {} additional seconds...", wait_interval);
+
+        sleep(Duration::from_secs(wait_interval));
+
+        info!("=> done!");
+    }
+
+    Ok(())
+}
+
+pub fn create(
+    config: &Config,
+    sessions: &mut Sessions,
+    domain: &str,
+    value: &str,
+) -> Result<(), ()> {
+    let is_ote = create_record(config, sessions, domain, value)?;
+
+    let mut records = Vec::new();
+    if !is_ote {
+        records.push((domain.to_owned(), value.to_owned()));
+    }
+
+    wait_for_propagation(config, &records)
+}
+
+pub fn delete(
+    config: &Config,
+    sessions: &mut Sessions,
+    domain: &str,
+    value: Option<&str>,
+) -> Result<(), ()> {
+    info!("Deleting TXT record...");
+
+    execute_api_commands(config, sessions, domain, |api| {
+        api.delete_txt_record(domain, value)?;
+        Ok(())
+    })?;
+
+    info!("=> done!");
+
+    Ok(())
+}
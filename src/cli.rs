@@ -1,154 +1,611 @@
-use crate::config::Config;
-use crate::dns::{check_txt_record, lookup_real_domain};
-use crate::inwx::{Inwx, InwxError};
-use clap::{App, Arg, SubCommand};
-use std::fs::File;
-use std::io::BufReader;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-
-fn execute_api_commands<F>(config: &Config, domain: &str, op: F) -> Result<bool, ()>
-where
-    F: Fn(&mut Inwx) -> Result<(), InwxError>,
-{
-    if config.accounts.len() == 0 {
-        error!("No accounts configured");
-        return Err(());
+use crate::acme::{challenge_name, challenge_value};
+use crate::acme_dns;
+use crate::challenge::{create, create_record, delete, wait_for_propagation, Sessions};
+use crate::config::{self, find_config, Config, ConfigError, ConfigFormat};
+use crate::daemon;
+use crate::dns::{lookup_real_domain, remove_trailing_dot};
+use crate::encryption;
+use crate::external_dns;
+use crate::httpreq;
+use crate::mock_server;
+use crate::powerdns;
+use crate::renew::{renew_certificates, Outcome};
+use crate::rfc2136;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+fn load_config(matches: &ArgMatches) -> Result<Config, ()> {
+    config::load_config(matches.value_of("configfile")).map_err(|e| error!("{}", e))
+}
+
+/// Follows the CNAME of the name with the dns server of its options.
+fn real_domain(config: &Config, name: &str) -> String {
+    lookup_real_domain(&config.options_for(name).dns_server, name)
+}
+
+fn certbot_env(name: &str) -> Result<String, ()> {
+    env::var(name).map_err(|_| {
+        error!(
+            "{} is not set, this subcommand has to be used as a certbot hook",
+            name
+        )
+    })
+}
+
+fn certbot_challenge_domain(config: &Config) -> Result<String, ()> {
+    let domain = certbot_env("CERTBOT_DOMAIN")?;
+
+    Ok(real_domain(config, &challenge_name(&domain)))
+}
+
+/// Returns the record name and value of a lego exec call, in RAW mode (`raw_mode` or a third
+/// argument) the value is the digest of the key authorization.
+fn lego_record(matches: &ArgMatches, raw_mode: bool) -> Result<(String, String), ()> {
+    let domain = matches.value_of("domain").unwrap();
+    let value = matches.value_of("value").unwrap();
+
+    if raw_mode || matches.value_of("key_auth").is_some() {
+        let key_authorization = matches.value_of("key_auth").ok_or_else(|| {
+            error!("The key authorization is required in RAW mode");
+        })?;
+        let value = challenge_value(key_authorization)
+            .map_err(|e| error!("Failed to compute the record value: {}", e))?;
+
+        Ok((challenge_name(domain), value))
+    } else {
+        Ok((remove_trailing_dot(domain), value.to_owned()))
     }
+}
 
-    let mut filtered_accounts = Vec::new();
+fn lego_challenge(config: &Config, matches: &ArgMatches) -> Result<(String, String), ()> {
+    let raw_mode = env::var("EXEC_MODE").is_ok_and(|mode| mode == "RAW");
+    let (name, value) = lego_record(matches, raw_mode)?;
 
-    match config.accounts.iter().find(|account| {
-        account
-            .domains
-            .iter()
-            .any(|d| domain == d || domain.ends_with(&format!(".{}", d)))
-    }) {
-        Some(account) => {
-            info!("Using account {}", account.username);
-            filtered_accounts.push(account);
+    Ok((real_domain(config, &name), value))
+}
+
+fn certbot_auth(config: &Config) -> Result<(), ()> {
+    let domain = certbot_challenge_domain(config)?;
+    let value = certbot_env("CERTBOT_VALIDATION")?;
+    let remaining: u32 = env::var("CERTBOT_REMAINING_CHALLENGES")
+        .ok()
+        .and_then(|remaining| remaining.parse().ok())
+        .unwrap_or(0);
+
+    let mut sessions = Sessions::new();
+    let result = create_record(config, &mut sessions, &domain, &value);
+    sessions.logout();
+    let is_ote = result?;
+
+    // the records of a batch can belong to different zones and accounts which propagate
+    // independently, so the last challenge waits for all of them
+    let path = certbot_batch_file();
+    let now = unix_time();
+    let mut batch = load_batch(&path, now);
+
+    if !is_ote {
+        batch.push(PendingRecord {
+            domain,
+            value,
+            created: now,
+        });
+    }
+
+    if remaining > 0 {
+        match save_batch(&path, &batch) {
+            Ok(()) => {
+                info!(
+                    "{} challenges remaining, waiting for the last one before checking the dns",
+                    remaining
+                );
+                return Ok(());
+            }
+            Err(e) => warn!(
+                "Failed to save the batch to {}, checking the dns now: {}",
+                path.display(),
+                e
+            ),
         }
-        None => {
-            warn!(
-                "Domain not configured: Trying {} configured accounts",
-                config.accounts.len()
-            );
-            filtered_accounts.extend(config.accounts.iter());
+    } else if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Failed to remove {}: {}", path.display(), e);
         }
+    }
+
+    let records: Vec<(String, String)> = batch
+        .into_iter()
+        .map(|record| (record.domain, record.value))
+        .collect();
+
+    wait_for_propagation(config, &records)
+}
+
+// records of a batch older than this were left behind by an aborted certbot run
+const BATCH_MAX_AGE: u64 = 60 * 60;
+
+/// A record created by `certbot-auth` which is checked after the last challenge of the batch.
+#[derive(Serialize, Deserialize)]
+struct PendingRecord {
+    domain: String,
+    value: String,
+    created: u64,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the file keeping the records of the current certbot batch, which is identified by
+/// `CERTBOT_ALL_DOMAINS`.
+fn certbot_batch_file() -> PathBuf {
+    let directory = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(directory) => PathBuf::from(directory).join("letsencrypt-inwx"),
+        None => PathBuf::from("/var/lib/letsencrypt-inwx"),
     };
 
-    for account in filtered_accounts {
-        let mut success = false;
-        let mut api = Inwx::new(&account).map_err(|e| error!("{}", e))?;
+    let domains = env::var("CERTBOT_ALL_DOMAINS").unwrap_or_default();
+    let hash: String = sha256(domains.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
 
-        match op(&mut api) {
-            Err(InwxError::DomainNotFound) => {}
-            Err(e) => {
-                error!("{}", e);
-                return Err(());
-            }
-            _ => {
-                success = true;
+    directory.join(format!("certbot-{}.json", hash))
+}
+
+/// Reads the records of the batch, a missing or unreadable file is an empty batch.
+fn load_batch(path: &Path, now: u64) -> Vec<PendingRecord> {
+    let records: Vec<PendingRecord> = fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    records
+        .into_iter()
+        .filter(|record| now.saturating_sub(record.created) < BATCH_MAX_AGE)
+        .collect()
+}
+
+fn save_batch(path: &Path, records: &[PendingRecord]) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+
+        builder.create(directory)?;
+    }
+
+    encryption::write_private(path, &serde_json::to_vec(records)?)
+}
+
+/// Turns the `<domain> <token> <value>` triples of a dehydrated hook call into records.
+fn dehydrated_challenges(config: &Config, args: &[&str]) -> Result<Vec<(String, String)>, ()> {
+    if args.is_empty() || !args.len().is_multiple_of(3) {
+        error!("Expected one or more <domain> <token> <value> triples");
+        return Err(());
+    }
+
+    Ok(args
+        .chunks(3)
+        .map(|challenge| {
+            (
+                real_domain(config, &challenge_name(challenge[0])),
+                challenge[2].to_owned(),
+            )
+        })
+        .collect())
+}
+
+fn dehydrated_hook(matches: &ArgMatches) -> Result<(), ()> {
+    let event = matches.value_of("event").unwrap();
+    let args: Vec<&str> = matches
+        .values_of("args")
+        .map(|args| args.collect())
+        .unwrap_or_default();
+
+    match event {
+        "deploy_challenge" => {
+            let config = load_config(matches)?;
+            let challenges = dehydrated_challenges(&config, &args)?;
+
+            let mut sessions = Sessions::new();
+            let mut records = Vec::new();
+            let mut result = Ok(());
+
+            for (domain, value) in challenges {
+                match create_record(&config, &mut sessions, &domain, &value) {
+                    Ok(false) => records.push((domain, value)),
+                    Ok(true) => {}
+                    Err(()) => {
+                        result = Err(());
+                        break;
+                    }
+                }
             }
+
+            sessions.logout();
+            result?;
+
+            wait_for_propagation(&config, &records)
         }
+        "clean_challenge" => {
+            let config = load_config(matches)?;
+            let challenges = dehydrated_challenges(&config, &args)?;
 
-        if let Err(e) = api.logout() {
-            error!("{}", e);
+            let mut sessions = Sessions::new();
+            let mut result = Ok(());
+
+            for (domain, value) in challenges {
+                if delete(&config, &mut sessions, &domain, Some(&value)).is_err() {
+                    result = Err(());
+                }
+            }
+
+            sessions.logout();
+
+            result
+        }
+        _ => {
+            debug!("Ignoring dehydrated event {}", event);
+            Ok(())
         }
+    }
+}
 
-        if success {
-            return Ok(account.ote);
+fn acme_sh_plugin(output: Option<&str>) -> Result<(), ()> {
+    let bin = env::current_exe()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "/usr/bin/letsencrypt-inwx".to_owned());
+    let plugin = include_str!("../etc/dns_letsencrypt_inwx.sh").replace("{{BIN}}", &bin);
+
+    match output {
+        Some(path) => {
+            fs::write(path, plugin).map_err(|e| error!("Failed to write the plugin: {}", e))?;
+            info!("Plugin written to {}", path);
         }
+        None => print!("{}", plugin),
     }
 
-    error!("{}", InwxError::DomainNotFound);
-    Err(())
+    Ok(())
 }
 
-fn read_config(path: &str) -> Result<Config, ()> {
-    let file = File::open(path).map_err(|e| error!("Failed to open config file: {}", e))?;
-    let reader = BufReader::new(file);
-    Ok(
-        serde_json::from_reader(reader)
-            .map_err(|e| error!("Failed to parse config file: {}", e))?,
-    )
+/// Handles the calls of the acme.sh plugin, acme.sh checks the dns itself after all records
+/// have been added, so the records are not waited for here.
+fn acme_sh(config: &Config, matches: &ArgMatches) -> Result<(), ()> {
+    let domain = real_domain(
+        config,
+        &remove_trailing_dot(matches.value_of("fulldomain").unwrap()),
+    );
+    let value = matches.value_of("txtvalue").unwrap();
+
+    let mut sessions = Sessions::new();
+    let result = match matches.value_of("action").unwrap() {
+        "add" => create_record(config, &mut sessions, &domain, value).map(|_| ()),
+        _ => delete(config, &mut sessions, &domain, Some(value)),
+    };
+    sessions.logout();
+
+    result
 }
 
-fn create(config: &Config, domain: &str, value: &str) -> Result<(), ()> {
-    info!("Creating TXT record...");
+fn certbot_cleanup(config: &Config) -> Result<(), ()> {
+    let domain = certbot_challenge_domain(config)?;
+    let value = certbot_env("CERTBOT_VALIDATION")?;
 
-    let is_ote = execute_api_commands(&config, &domain, |api| {
-        api.create_txt_record(&domain, &value)?;
+    let mut sessions = Sessions::new();
+    let result = delete(config, &mut sessions, &domain, Some(&value));
+    sessions.logout();
+
+    result
+}
+
+fn renew(config: &Config, names: &[&str], force: bool) -> Result<(), ()> {
+    if config.certificates.is_empty() {
+        error!("No certificates configured");
+        return Err(());
+    }
+
+    for name in names {
+        if !config.certificates.iter().any(|c| c.name == *name) {
+            error!("Certificate not configured: {}", name);
+            return Err(());
+        }
+    }
+
+    let mut sessions = Sessions::new();
+    let results = renew_certificates(config, &mut sessions, names, force);
+    sessions.logout();
+
+    if results
+        .iter()
+        .any(|result| result.outcome == Outcome::Failed)
+    {
+        Err(())
+    } else {
         Ok(())
-    })?;
+    }
+}
 
-    info!("=> done!");
+fn status(config: &Config) -> Result<(), ()> {
+    let status = daemon::read_status(config)?;
 
-    if !is_ote && !config.options.no_dns_check {
-        info!("Waiting for the dns record to be publicly visible...");
+    println!("{}", serde_json::to_string_pretty(&status).unwrap());
 
-        let start = Instant::now();
-        let mut wait_secs = 5;
+    if status.is_healthy() {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
 
-        loop {
-            // timeout after 10 minutes
-            if start.elapsed() > Duration::from_secs(60 * 10) {
-                error!("=> timeout!");
-                return Err(());
-            }
+fn key_file(matches: &ArgMatches) -> PathBuf {
+    matches
+        .value_of("keyfile")
+        .map(PathBuf::from)
+        .unwrap_or_else(encryption::find_key_file)
+}
 
-            if check_txt_record(&config.options.dns_server, &domain, value) {
-                break;
-            }
+fn read_key(path: &Path) -> Result<Zeroizing<Vec<u8>>, ()> {
+    encryption::read_key(path).map_err(|e| error!("{}", e))
+}
 
-            wait_secs *= 2;
+fn read_file(path: &str) -> Result<Vec<u8>, ()> {
+    fs::read(path).map_err(|e| error!("Failed to read {}: {}", path, e))
+}
 
-            sleep(Duration::from_secs(wait_secs));
-        }
+fn write_private(path: &str, data: &[u8]) -> Result<(), ()> {
+    encryption::write_private(Path::new(path), data)
+        .map_err(|e| error!("Failed to write {}: {}", path, e))
+}
+
+fn check_config(format: ConfigFormat, data: &[u8]) -> Result<(), ()> {
+    format
+        .parse_config(data)
+        .map(|_| ())
+        .map_err(|e| error!("{}", e))
+}
+
+fn decrypt_config(path: &str, key: &[u8]) -> Result<Zeroizing<Vec<u8>>, ()> {
+    let data = read_file(path)?;
+
+    if !encryption::is_encrypted(&data) {
+        error!("{} is not encrypted", path);
+        return Err(());
+    }
+
+    encryption::decrypt(key, &data)
+        .map(Zeroizing::new)
+        .map_err(|e| error!("{}", e))
+}
+
+fn config_encrypt(matches: &ArgMatches) -> Result<(), ()> {
+    let path = matches.value_of("configfile").unwrap();
+    let data = read_file(path)?;
 
-        info!("=> done!");
+    if encryption::is_encrypted(&data) {
+        error!("{} is already encrypted", path);
+        return Err(());
     }
 
-    if config.options.wait_interval > 0 {
-        info!(
-            "Waiting {} additional seconds...",
-            &config.options.wait_interval
-        );
+    check_config(ConfigFormat::detect(path, &data), &data)?;
 
-        sleep(Duration::from_secs(config.options.wait_interval));
+    let key_file = key_file(matches);
 
-        info!("=> done!");
+    if !key_file.exists() {
+        encryption::create_key_file(&key_file).map_err(|e| error!("{}", e))?;
+        info!("Created the key file {}", key_file.display());
     }
 
+    let encrypted =
+        encryption::encrypt(&read_key(&key_file)?, &data).map_err(|e| error!("{}", e))?;
+    let output = matches.value_of("output").unwrap_or(path);
+
+    write_private(output, &encrypted)?;
+    info!(
+        "Encrypted {} with the key file {}",
+        output,
+        key_file.display()
+    );
+
     Ok(())
 }
 
-fn delete(config: &Config, domain: &str) -> Result<(), ()> {
-    info!("Deleting TXT record...");
+fn config_decrypt(matches: &ArgMatches) -> Result<(), ()> {
+    let key = read_key(&key_file(matches))?;
+    let data = decrypt_config(matches.value_of("configfile").unwrap(), &key)?;
 
-    execute_api_commands(&config, &domain, |api| {
-        api.delete_txt_record(&domain)?;
-        Ok(())
-    })?;
+    match matches.value_of("output") {
+        Some(output) => write_private(output, &data),
+        None => io::stdout()
+            .write_all(&data)
+            .map_err(|e| error!("Failed to print the config: {}", e)),
+    }
+}
+
+/// Lets the user edit the data in a private directory, which is removed afterwards.
+fn edit(data: &[u8], format: ConfigFormat) -> Result<Zeroizing<Vec<u8>>, ()> {
+    let mut suffix = [0; 8];
+    rand_bytes(&mut suffix).map_err(|e| error!("{}", e))?;
+    let suffix: String = suffix.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    let directory = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("letsencrypt-inwx-{}", suffix));
+
+    let mut builder = DirBuilder::new();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
 
-    info!("=> done!");
+    builder
+        .create(&directory)
+        .map_err(|e| error!("Failed to create {}: {}", directory.display(), e))?;
+
+    // the extension lets the editor highlight the format
+    let path = directory.join(format!("config.{}", format.extension()));
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+
+    let result = encryption::write_private(&path, data)
+        .map_err(|e| error!("Failed to write {}: {}", path.display(), e))
+        .and_then(|_| {
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", editor))
+                .arg("sh")
+                .arg(&path)
+                .status()
+                .map_err(|e| error!("Failed to run {}: {}", editor, e))
+        })
+        .and_then(|status| match status.success() {
+            true => fs::read(&path)
+                .map(Zeroizing::new)
+                .map_err(|e| error!("Failed to read {}: {}", path.display(), e)),
+            false => {
+                error!("{} failed with {}", editor, status);
+                Err(())
+            }
+        });
+
+    if let Err(e) = fs::remove_dir_all(&directory) {
+        warn!("Failed to remove {}: {}", directory.display(), e);
+    }
+
+    result
+}
+
+fn config_edit(matches: &ArgMatches) -> Result<(), ()> {
+    let path = matches.value_of("configfile").unwrap();
+    let key = read_key(&key_file(matches))?;
+    let data = decrypt_config(path, &key)?;
+
+    let format = ConfigFormat::detect(path, &data);
+    let edited = edit(&data, format)?;
+
+    if edited == data {
+        info!("The config was not changed");
+        return Ok(());
+    }
+
+    check_config(format, &edited).map_err(|_| error!("The changes were discarded"))?;
+
+    let encrypted = encryption::encrypt(&key, &edited).map_err(|e| error!("{}", e))?;
+    write_private(path, &encrypted)?;
+    info!("Saved {}", path);
 
     Ok(())
 }
 
-pub fn run() -> Result<(), ()> {
-    let mut app = App::new("letsencrypt-inwx")
+/// Prints every problem of the config with its location, neither the passwords are resolved
+/// nor is the inwx api called.
+fn config_check(matches: &ArgMatches) -> Result<(), ()> {
+    let path = match matches.value_of("configfile") {
+        Some(path) => path.to_owned(),
+        None => find_config().ok_or_else(|| error!("{}", ConfigError::NotFound))?,
+    };
+    let data = config::read_config_file(&path).map_err(|e| error!("{}", e))?;
+
+    match ConfigFormat::detect(&path, &data).parse_config(&data) {
+        Ok(_) => {
+            println!("{} is valid", path);
+            Ok(())
+        }
+        Err(ConfigError::Invalid(problems)) => {
+            for problem in problems {
+                println!("{}: {}", path, problem);
+            }
+
+            Err(())
+        }
+        Err(e) => {
+            error!("{}", e);
+            Err(())
+        }
+    }
+}
+
+/// Writes the config in the format of `-f` or the extension of the output file, the output is
+/// encrypted with the same key if the input is.
+fn config_convert(matches: &ArgMatches) -> Result<(), ()> {
+    let path = matches.value_of("configfile").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let data = Zeroizing::new(read_file(path)?);
+
+    let key = match encryption::is_encrypted(&data) {
+        true => Some(read_key(&key_file(matches))?),
+        false => None,
+    };
+
+    let data = match key {
+        Some(ref key) => {
+            Zeroizing::new(encryption::decrypt(key, &data).map_err(|e| error!("{}", e))?)
+        }
+        None => data,
+    };
+
+    let from = ConfigFormat::detect(path, &data);
+    let to = match matches.value_of("format") {
+        Some(format) => ConfigFormat::from_name(format).ok_or_else(|| {
+            error!("Unknown format {}, use json, toml or yaml", format);
+        })?,
+        None => ConfigFormat::from_path(output).ok_or_else(|| {
+            error!("The format of {} is unknown, specify it with -f", output);
+        })?,
+    };
+
+    let converted = config::convert(&data, from, to).map_err(|e| error!("{}", e))?;
+
+    match key {
+        Some(ref key) => write_private(
+            output,
+            &encryption::encrypt(key, converted.as_bytes()).map_err(|e| error!("{}", e))?,
+        )?,
+        None => write_private(output, converted.as_bytes())?,
+    }
+
+    info!("Converted {} ({}) to {} ({})", path, from, output, to);
+
+    Ok(())
+}
+
+/// The `-c` option of the subcommands using a config file.
+fn config_arg() -> Arg<'static, 'static> {
+    Arg::with_name("configfile")
+        .short("c")
+        .value_name("CONFIG_FILE")
+        .help("specify the path to the configfile, default: $LETSENCRYPT_INWX_CONFIG or letsencrypt-inwx.{json,toml,yaml} in ~/.config or /etc")
+        .takes_value(true)
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("letsencrypt-inwx")
         .version(env!("CARGO_PKG_VERSION"))
         .about("A small cli utility for automating the letsencrypt dns-01 challenge for domains hosted by inwx")
         .subcommand(SubCommand::with_name("create")
             .about("create a TXT record")
-            .arg(Arg::with_name("configfile")
-                .short("c")
-                .value_name("CONFIG_FILE")
-                .help("specify the path to the configfile")
-                .takes_value(true)
-                .required(true)
-            )
+            .arg(config_arg())
             .arg(Arg::with_name("domain")
                 .short("d")
                 .value_name("DOMAIN")
@@ -166,13 +623,7 @@ pub fn run() -> Result<(), ()> {
         )
         .subcommand(SubCommand::with_name("delete")
             .about("delete a TXT record")
-            .arg(Arg::with_name("configfile")
-                .short("c")
-                .value_name("CONFIG_FILE")
-                .help("specify the path to the configfile")
-                .takes_value(true)
-                .required(true)
-            )
+            .arg(config_arg())
             .arg(Arg::with_name("domain")
                 .short("d")
                 .value_name("DOMAIN")
@@ -180,27 +631,362 @@ pub fn run() -> Result<(), ()> {
                 .takes_value(true)
                 .required(true)
             )
-        );
+            .arg(Arg::with_name("value")
+                .short("v")
+                .value_name("VALUE")
+                .help("only delete the record with this value")
+                .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("renew")
+            .about("issue or renew the certificates configured in the configfile")
+            .arg(config_arg())
+            .arg(Arg::with_name("name")
+                .short("n")
+                .value_name("NAME")
+                .help("only renew the certificate with this name, can be specified multiple times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("issue the certificates even if they are not due for renewal")
+            )
+        )
+        .subcommand(SubCommand::with_name("daemon")
+            .about("keep running and renew the configured certificates periodically, reload the configfile on SIGHUP")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("status")
+            .about("print the status of a running daemon, exits with 1 if it is unhealthy")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("certbot-auth")
+            .about("create the TXT record as a certbot --manual-auth-hook")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("certbot-cleanup")
+            .about("delete the TXT record as a certbot --manual-cleanup-hook")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("present")
+            .setting(AppSettings::AllowLeadingHyphen)
+            .about("create a TXT record as a lego exec provider (\"present <fqdn> <value>\" or \"present <domain> <token> <key_auth>\" in RAW mode)")
+            .arg(config_arg())
+            .arg(Arg::with_name("domain")
+                .value_name("FQDN")
+                .help("the name of the record, the domain in RAW mode")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("value")
+                .value_name("VALUE")
+                .help("the value of the record, the token in RAW mode")
+                .required(true)
+                .index(2)
+            )
+            .arg(Arg::with_name("key_auth")
+                .value_name("KEY_AUTH")
+                .help("the key authorization, only passed in RAW mode, the record value is its digest")
+                .index(3)
+            )
+        )
+        .subcommand(SubCommand::with_name("cleanup")
+            .setting(AppSettings::AllowLeadingHyphen)
+            .about("delete a TXT record as a lego exec provider (\"cleanup <fqdn> <value>\" or \"cleanup <domain> <token> <key_auth>\" in RAW mode)")
+            .arg(config_arg())
+            .arg(Arg::with_name("domain")
+                .value_name("FQDN")
+                .help("the name of the record, the domain in RAW mode")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("value")
+                .value_name("VALUE")
+                .help("the value of the record, the token in RAW mode")
+                .required(true)
+                .index(2)
+            )
+            .arg(Arg::with_name("key_auth")
+                .value_name("KEY_AUTH")
+                .help("the key authorization, only passed in RAW mode, the record value is its digest")
+                .index(3)
+            )
+        )
+        .subcommand(SubCommand::with_name("dehydrated-hook")
+            .setting(AppSettings::AllowLeadingHyphen)
+            .about("handle a dehydrated hook call, only deploy_challenge and clean_challenge are processed")
+            .arg(config_arg())
+            .arg(Arg::with_name("event")
+                .value_name("EVENT")
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("args")
+                .value_name("ARGS")
+                .multiple(true)
+                .index(2)
+            )
+        )
+        .subcommand(SubCommand::with_name("acme-sh-plugin")
+            .about("print the acme.sh dnsapi plugin dns_letsencrypt_inwx.sh")
+            .arg(Arg::with_name("output")
+                .short("o")
+                .value_name("FILE")
+                .help("write the plugin to this file instead of stdout")
+                .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("acme-sh")
+            .setting(AppSettings::AllowLeadingHyphen)
+            .about("add or remove a TXT record for the acme.sh dnsapi plugin")
+            .arg(config_arg())
+            .arg(Arg::with_name("action")
+                .value_name("ACTION")
+                .possible_values(&["add", "rm"])
+                .required(true)
+                .index(1)
+            )
+            .arg(Arg::with_name("fulldomain")
+                .value_name("FULLDOMAIN")
+                .required(true)
+                .index(2)
+            )
+            .arg(Arg::with_name("txtvalue")
+                .value_name("TXTVALUE")
+                .required(true)
+                .index(3)
+            )
+        )
+        .subcommand(SubCommand::with_name("serve-acme-dns")
+            .about("serve an acme-dns compatible api which creates the records at inwx")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("serve-external-dns")
+            .about("serve the external-dns webhook provider api for the domains of the accounts")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("serve-httpreq")
+            .about("serve the api of lego's httpreq provider which creates the records at inwx")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("config")
+            .about("check, encrypt, decrypt, edit or convert a config file")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("encrypt")
+                .about("encrypt a config file in place, the key file is created if it does not exist")
+                .arg(config_arg()
+                    .help("specify the path to the configfile")
+                    .required(true)
+                )
+                .arg(Arg::with_name("keyfile")
+                    .short("k")
+                    .value_name("KEY_FILE")
+                    .help("the key file, default: $LETSENCRYPT_INWX_CONFIG_KEY_FILE or /etc/letsencrypt-inwx.key")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .value_name("OUTPUT_FILE")
+                    .help("write the encrypted config to this file instead")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("decrypt")
+                .about("print the decrypted config")
+                .arg(config_arg()
+                    .help("specify the path to the configfile")
+                    .required(true)
+                )
+                .arg(Arg::with_name("keyfile")
+                    .short("k")
+                    .value_name("KEY_FILE")
+                    .help("the key file, default: $LETSENCRYPT_INWX_CONFIG_KEY_FILE or /etc/letsencrypt-inwx.key")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .value_name("OUTPUT_FILE")
+                    .help("write the decrypted config to this file instead")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("check")
+                .about("validate a config file and print every problem, the inwx api is not called")
+                .arg(config_arg())
+            )
+            .subcommand(SubCommand::with_name("convert")
+                .about("convert a config file between json, toml and yaml, an encrypted config stays encrypted")
+                .arg(config_arg()
+                    .help("specify the path to the configfile")
+                    .required(true)
+                )
+                .arg(Arg::with_name("output")
+                    .short("o")
+                    .value_name("OUTPUT_FILE")
+                    .help("write the converted config to this file")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("format")
+                    .short("f")
+                    .value_name("FORMAT")
+                    .help("the format of the output, default: the extension of the output file")
+                    .possible_values(&["json", "toml", "yaml"])
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("keyfile")
+                    .short("k")
+                    .value_name("KEY_FILE")
+                    .help("the key file, default: $LETSENCRYPT_INWX_CONFIG_KEY_FILE or /etc/letsencrypt-inwx.key")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("edit")
+                .about("edit an encrypted config with $VISUAL or $EDITOR")
+                .arg(config_arg()
+                    .help("specify the path to the configfile")
+                    .required(true)
+                )
+                .arg(Arg::with_name("keyfile")
+                    .short("k")
+                    .value_name("KEY_FILE")
+                    .help("the key file, default: $LETSENCRYPT_INWX_CONFIG_KEY_FILE or /etc/letsencrypt-inwx.key")
+                    .takes_value(true)
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("mock-server")
+            .about("serve a mock of the inwx api with in-memory zones for offline tests, point the api_url of an account at it")
+            .arg(Arg::with_name("listen")
+                .short("l")
+                .value_name("ADDRESS")
+                .help("the address to listen on, default: 127.0.0.1:8091")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("seedfile")
+                .short("s")
+                .value_name("SEED_FILE")
+                .help("specify the path to the json file with the initial zones")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("serve-powerdns")
+            .about("serve a PowerDNS compatible http api which changes the records at inwx")
+            .arg(config_arg())
+        )
+        .subcommand(SubCommand::with_name("serve-rfc2136")
+            .about("serve rfc2136 dynamic updates signed with tsig and apply them at inwx")
+            .arg(config_arg())
+        )
+}
 
+pub fn run() -> Result<(), ()> {
+    let mut app = app();
     let matches = app.clone().get_matches();
 
     if let Some(matches) = matches.subcommand_matches("create") {
-        let config = read_config(matches.value_of("configfile").unwrap())?;
-        let domain = lookup_real_domain(
-            &config.options.dns_server,
-            matches.value_of("domain").unwrap(),
-        );
+        let config = load_config(matches)?;
+        let domain = real_domain(&config, matches.value_of("domain").unwrap());
         let value = matches.value_of("value").unwrap();
 
-        create(&config, &domain, &value)?;
+        let mut sessions = Sessions::new();
+        let result = create(&config, &mut sessions, &domain, value);
+        sessions.logout();
+        result?;
     } else if let Some(matches) = matches.subcommand_matches("delete") {
-        let config = read_config(matches.value_of("configfile").unwrap())?;
-        let domain = lookup_real_domain(
-            &config.options.dns_server,
-            matches.value_of("domain").unwrap(),
-        );
+        let config = load_config(matches)?;
+        let domain = real_domain(&config, matches.value_of("domain").unwrap());
+
+        let mut sessions = Sessions::new();
+        let result = delete(&config, &mut sessions, &domain, matches.value_of("value"));
+        sessions.logout();
+        result?;
+    } else if let Some(matches) = matches.subcommand_matches("renew") {
+        let config = load_config(matches)?;
+        let names: Vec<&str> = matches
+            .values_of("name")
+            .map(|names| names.collect())
+            .unwrap_or_default();
+
+        renew(&config, &names, matches.is_present("force"))?;
+    } else if let Some(matches) = matches.subcommand_matches("daemon") {
+        daemon::run(matches.value_of("configfile"))?;
+    } else if let Some(matches) = matches.subcommand_matches("status") {
+        let config = load_config(matches)?;
+
+        status(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("certbot-auth") {
+        let config = load_config(matches)?;
+
+        certbot_auth(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("certbot-cleanup") {
+        let config = load_config(matches)?;
+
+        certbot_cleanup(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("dehydrated-hook") {
+        dehydrated_hook(matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("acme-sh-plugin") {
+        acme_sh_plugin(matches.value_of("output"))?;
+    } else if let Some(matches) = matches.subcommand_matches("acme-sh") {
+        let config = load_config(matches)?;
+
+        acme_sh(&config, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("serve-acme-dns") {
+        let config = load_config(matches)?;
+
+        acme_dns::serve(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("serve-rfc2136") {
+        let config = load_config(matches)?;
+
+        rfc2136::serve(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("serve-external-dns") {
+        let config = load_config(matches)?;
 
-        delete(&config, &domain)?;
+        external_dns::serve(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("serve-httpreq") {
+        let config = load_config(matches)?;
+
+        httpreq::serve(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("config") {
+        if let Some(matches) = matches.subcommand_matches("encrypt") {
+            config_encrypt(matches)?;
+        } else if let Some(matches) = matches.subcommand_matches("decrypt") {
+            config_decrypt(matches)?;
+        } else if let Some(matches) = matches.subcommand_matches("edit") {
+            config_edit(matches)?;
+        } else if let Some(matches) = matches.subcommand_matches("check") {
+            config_check(matches)?;
+        } else if let Some(matches) = matches.subcommand_matches("convert") {
+            config_convert(matches)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("mock-server") {
+        mock_server::serve(
+            matches.value_of("listen").unwrap_or("127.0.0.1:8091"),
+            matches.value_of("seedfile").unwrap(),
+        )?;
+    } else if let Some(matches) = matches.subcommand_matches("serve-powerdns") {
+        let config = load_config(matches)?;
+
+        powerdns::serve(&config)?;
+    } else if let Some(matches) = matches.subcommand_matches("present") {
+        let config = load_config(matches)?;
+        let (domain, value) = lego_challenge(&config, matches)?;
+
+        let mut sessions = Sessions::new();
+        let result = create(&config, &mut sessions, &domain, &value);
+        sessions.logout();
+        result?;
+    } else if let Some(matches) = matches.subcommand_matches("cleanup") {
+        let config = load_config(matches)?;
+        let (domain, value) = lego_challenge(&config, matches)?;
+
+        let mut sessions = Sessions::new();
+        let result = delete(&config, &mut sessions, &domain, Some(&value));
+        sessions.logout();
+        result?;
     } else {
         app.print_help().unwrap();
         std::process::exit(1);
@@ -208,3 +994,87 @@ pub fn run() -> Result<(), ()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn lego(args: &[&str], raw_mode: bool) -> Result<(String, String), ()> {
+        let matches = app()
+            .get_matches_from_safe(["letsencrypt-inwx"].iter().chain(args))
+            .unwrap();
+        let (_, matches) = matches.subcommand();
+
+        lego_record(matches.unwrap(), raw_mode)
+    }
+
+    #[test]
+    fn lego_arguments() {
+        let record = |name: &str, value: &str| Ok((name.to_owned(), value.to_owned()));
+        // the digest of the key authorization, as computed by lego
+        let digest = "61rBZ_4knHblO0MNoxFsXZ_eTFUHum0B6IVRbhvUn5I";
+
+        assert_eq!(
+            lego(
+                &["present", "_acme-challenge.example.com.", "-value"],
+                false
+            ),
+            record("_acme-challenge.example.com", "-value")
+        );
+        assert_eq!(
+            lego(
+                &[
+                    "cleanup",
+                    "-c",
+                    "config.json",
+                    "_acme-challenge.example.com.",
+                    "value"
+                ],
+                false
+            ),
+            record("_acme-challenge.example.com", "value")
+        );
+
+        // RAW mode is detected by the key authorization as well
+        assert_eq!(
+            lego(
+                &["present", "*.example.com.", "token", "token.thumbprint"],
+                false
+            ),
+            record("_acme-challenge.example.com", digest)
+        );
+        assert_eq!(
+            lego(
+                &["cleanup", "example.com", "token", "token.thumbprint"],
+                true
+            ),
+            record("_acme-challenge.example.com", digest)
+        );
+        assert_eq!(lego(&["present", "example.com", "token"], true), Err(()));
+    }
+
+    #[test]
+    fn certbot_batch() {
+        let directory = TempDir::new("certbot-batch");
+        let path = directory.join("letsencrypt-inwx").join("batch.json");
+        let record = |domain: &str, created| PendingRecord {
+            domain: domain.to_owned(),
+            value: "value".to_owned(),
+            created,
+        };
+
+        assert!(load_batch(&path, 0).is_empty());
+
+        save_batch(
+            &path,
+            &[record("example.com", 1000), record("example.org", 5000)],
+        )
+        .unwrap();
+
+        // the record of an aborted batch is dropped
+        let batch = load_batch(&path, 5000);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].domain, "example.org");
+    }
+}
@@ -0,0 +1,318 @@
+use crate::challenge::Sessions;
+use crate::config::{load_config, Config};
+use crate::renew::{renew_certificates, Outcome, RenewResult};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// the status file is rewritten at least this often, so a stale file indicates a hung daemon
+const HEARTBEAT_SECS: u64 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Status {
+    pub healthy: bool,
+    pub updated_at: u64,
+    pub last_run: Option<u64>,
+    pub next_run: u64,
+    pub results: Vec<RenewResult>,
+}
+
+impl Status {
+    /// A status is only considered healthy if the last run succeeded and the daemon is still alive.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy && unix_time().saturating_sub(self.updated_at) <= HEARTBEAT_SECS * 5
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn write_status(config: &Config, status: &Status) {
+    if let Some(ref path) = config.daemon.status_file {
+        let json = serde_json::to_vec_pretty(status).unwrap();
+        let tmp_path = format!("{}.tmp", path);
+
+        if let Err(e) = fs::write(&tmp_path, json).and_then(|_| fs::rename(&tmp_path, path)) {
+            error!("Failed to write status file: {}", e);
+        }
+    }
+}
+
+pub fn read_status(config: &Config) -> Result<Status, ()> {
+    let path = config.daemon.status_file.as_ref().ok_or_else(|| {
+        error!("No status file configured");
+    })?;
+
+    let json = fs::read(path).map_err(|e| error!("Failed to read status file: {}", e))?;
+    serde_json::from_slice(&json).map_err(|e| error!("Failed to parse status file: {}", e))
+}
+
+/// The state of the daemon between the iterations of its loop.
+struct Daemon<'a> {
+    config_path: Option<&'a str>,
+    config: Config,
+    sessions: Sessions,
+    status: Status,
+    next_run: Instant,
+    next_heartbeat: Instant,
+}
+
+impl<'a> Daemon<'a> {
+    fn new(config_path: Option<&'a str>, now: Instant) -> Result<Daemon<'a>, ()> {
+        Ok(Daemon {
+            config_path,
+            config: load_config(config_path).map_err(|e| error!("{}", e))?,
+            sessions: Sessions::new(),
+            status: Status {
+                healthy: true,
+                ..Status::default()
+            },
+            next_run: now,
+            next_heartbeat: now,
+        })
+    }
+
+    fn reload(&mut self, now: Instant) {
+        info!("Reloading config...");
+
+        match load_config(self.config_path) {
+            Ok(config) => {
+                // the credentials may have changed, so don't reuse the old sessions
+                self.sessions.logout();
+                self.config = config;
+                self.next_run = now;
+
+                info!("=> done!");
+            }
+            Err(e) => {
+                error!("{}", e);
+                warn!("Keeping the previous config");
+            }
+        }
+    }
+
+    /// Renews the certificates once they are due and writes the status file every heartbeat.
+    fn tick(&mut self, now: Instant) {
+        if now >= self.next_run {
+            let status = &mut self.status;
+
+            status.results = renew_certificates(&self.config, &mut self.sessions, &[], false);
+            status.healthy = status
+                .results
+                .iter()
+                .all(|result| result.outcome != Outcome::Failed);
+            status.last_run = Some(unix_time());
+
+            let interval = match status.healthy {
+                true => self.config.daemon.interval,
+                false => self.config.daemon.retry_interval,
+            };
+
+            info!("Next renewal check in {} seconds", interval);
+
+            // the intervals are limited by the validation of the config
+            self.next_run = now + Duration::from_secs(interval);
+            status.next_run = unix_time() + interval;
+            self.next_heartbeat = now;
+        }
+
+        if now >= self.next_heartbeat {
+            self.status.updated_at = unix_time();
+            write_status(&self.config, &self.status);
+
+            self.next_heartbeat = now + Duration::from_secs(HEARTBEAT_SECS);
+        }
+    }
+}
+
+pub fn run(config_path: Option<&str>) -> Result<(), ()> {
+    let reload = Arc::new(AtomicBool::new(false));
+    let terminate = Arc::new(AtomicBool::new(false));
+
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))
+        .and_then(|_| signal_hook::flag::register(SIGTERM, Arc::clone(&terminate)))
+        .and_then(|_| signal_hook::flag::register(SIGINT, Arc::clone(&terminate)))
+        .map_err(|e| error!("Failed to register signal handlers: {}", e))?;
+
+    let mut daemon = Daemon::new(config_path, Instant::now())?;
+
+    info!("Daemon started");
+
+    while !terminate.load(Ordering::Relaxed) {
+        if reload.swap(false, Ordering::Relaxed) {
+            daemon.reload(Instant::now());
+        }
+
+        daemon.tick(Instant::now());
+
+        sleep(Duration::from_secs(1));
+    }
+
+    info!("Shutting down...");
+
+    daemon.sessions.logout();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use serde_json::json;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Writes the config with the status file of the directory and the certificate paths.
+    fn write_config(directory: &TempDir, interval: u64, cert_paths: &[&str]) -> String {
+        let path = directory.join("config.json");
+        let certificates: Vec<_> = cert_paths
+            .iter()
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "domains": ["example.com"],
+                    "cert_path": directory.join(name),
+                    "key_path": directory.join("key.pem"),
+                })
+            })
+            .collect();
+
+        let config = json!({
+            "records_file": {
+                "path": directory.join("records.json"),
+                "zones": ["example.com"],
+            },
+            "daemon": {
+                "interval": interval,
+                "retry_interval": 60,
+                "status_file": directory.join("status.json"),
+            },
+            "certificates": certificates,
+        });
+
+        fs::write(&path, config.to_string()).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn status(directory: &TempDir) -> Option<Status> {
+        fs::read(directory.join("status.json"))
+            .ok()
+            .map(|json| serde_json::from_slice(&json).unwrap())
+    }
+
+    #[test]
+    fn heartbeat() {
+        let directory = TempDir::new("daemon-heartbeat");
+        let path = write_config(&directory, 3600, &[]);
+        let start = Instant::now();
+        let mut daemon = Daemon::new(Some(&path), start).unwrap();
+
+        daemon.tick(start);
+
+        let written = status(&directory).unwrap();
+        assert!(written.is_healthy());
+        assert!(written.last_run.is_some());
+        assert!(written.next_run >= unix_time() + 3599);
+        assert_eq!(daemon.next_run, start + Duration::from_secs(3600));
+
+        // the status is only rewritten once the heartbeat is due
+        fs::remove_file(directory.join("status.json")).unwrap();
+        daemon.tick(start + Duration::from_secs(HEARTBEAT_SECS - 1));
+        assert!(status(&directory).is_none());
+
+        daemon.tick(start + Duration::from_secs(HEARTBEAT_SECS));
+        let rewritten = status(&directory).unwrap();
+        assert!(rewritten.is_healthy());
+        assert_eq!(rewritten.last_run, written.last_run);
+
+        let stale = Status {
+            updated_at: unix_time() - HEARTBEAT_SECS * 5 - 1,
+            ..rewritten
+        };
+        assert!(!stale.is_healthy());
+    }
+
+    #[test]
+    fn retry_failed_renewals() {
+        let directory = TempDir::new("daemon-retry");
+        fs::write(directory.join("invalid.pem"), "not a certificate").unwrap();
+        let path = write_config(&directory, 3600, &["invalid.pem"]);
+        let start = Instant::now();
+        let mut daemon = Daemon::new(Some(&path), start).unwrap();
+
+        daemon.tick(start);
+
+        let written = status(&directory).unwrap();
+        assert!(!written.is_healthy());
+        assert_eq!(written.results.len(), 1);
+        assert_eq!(written.results[0].outcome, Outcome::Failed);
+        assert_eq!(daemon.next_run, start + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn reload() {
+        let directory = TempDir::new("daemon-reload");
+        let path = write_config(&directory, 3600, &[]);
+        let start = Instant::now();
+        let mut daemon = Daemon::new(Some(&path), start).unwrap();
+
+        daemon.tick(start);
+        assert_eq!(daemon.next_run, start + Duration::from_secs(3600));
+
+        // a reload runs the renewal check with the new config right away
+        write_config(&directory, 7200, &[]);
+        let reloaded = start + Duration::from_secs(10);
+        daemon.reload(reloaded);
+        assert_eq!(daemon.next_run, reloaded);
+
+        daemon.tick(reloaded);
+        assert_eq!(daemon.next_run, reloaded + Duration::from_secs(7200));
+
+        // an invalid config keeps the previous one
+        fs::write(&path, "{ \"unknown\": true }").unwrap();
+        daemon.reload(reloaded);
+        assert_eq!(daemon.config.daemon.interval, 7200);
+        assert_eq!(daemon.next_run, reloaded + Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn signals() {
+        let directory = TempDir::new("daemon-signals");
+        let path = write_config(&directory, 3600, &[]);
+
+        let wait_for = |condition: &dyn Fn(&Status) -> bool| {
+            for _ in 0..100 {
+                if status(&directory).is_some_and(|status| condition(&status)) {
+                    return;
+                }
+                sleep(Duration::from_millis(100));
+            }
+            panic!("the daemon did not update its status");
+        };
+
+        let daemon = {
+            let path = path.clone();
+            std::thread::spawn(move || run(Some(&path)))
+        };
+
+        // the handlers are registered once the first status has been written
+        wait_for(&|status| status.next_run >= unix_time() + 3500);
+
+        write_config(&directory, 7200, &[]);
+        signal_hook::low_level::raise(SIGHUP).unwrap();
+        wait_for(&|status| status.next_run >= unix_time() + 7100);
+
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        assert!(daemon.join().unwrap().is_ok());
+    }
+}
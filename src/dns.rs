@@ -1,15 +1,46 @@
-use std::str::FromStr;
-use trust_dns::client::{Client, SyncClient};
-use trust_dns::op::DnsResponse;
-use trust_dns::rr::{DNSClass, Name, RData, Record, RecordType};
-use trust_dns::udp::UdpClientConnection;
-
-fn dns_client(dns_server: &str) -> SyncClient<UdpClientConnection> {
-    let address = format!("{}:53", dns_server).parse().unwrap();
-    let conn = UdpClientConnection::new(address).unwrap();
-    SyncClient::new(conn)
+use std::future::Future;
+use std::net::IpAddr;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::TokioAsyncResolver;
+
+fn resolver(dns_server: &str) -> Option<TokioAsyncResolver> {
+    let ip: IpAddr = match dns_server.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            error!("The dns server {} is not an ip address", dns_server);
+            return None;
+        }
+    };
+
+    let config = ResolverConfig::from_parts(
+        None,
+        vec![],
+        NameServerConfigGroup::from_ips_clear(&[ip], 53, true),
+    );
+
+    // every check has to reach the dns server, a cached answer would hide the propagation
+    let mut options = ResolverOpts::default();
+    options.cache_size = 0;
+
+    Some(TokioAsyncResolver::tokio(config, options))
+}
+
+/// Runs a future of the async api on a runtime of its own, `None` if it can't be created.
+fn block_on<F: Future>(future: F) -> Option<F::Output> {
+    match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => Some(runtime.block_on(future)),
+        Err(e) => {
+            error!("Failed to create the tokio runtime: {}", e);
+            None
+        }
+    }
 }
 
+/// Turns a domain into an absolute name, i.e. `example.com.`.
 pub fn add_trailing_dot(domain: &str) -> String {
     let mut domain = domain.to_owned();
 
@@ -20,6 +51,7 @@ pub fn add_trailing_dot(domain: &str) -> String {
     domain
 }
 
+/// Removes the trailing dot of an absolute name.
 pub fn remove_trailing_dot(domain: &str) -> String {
     let mut domain = domain.to_owned();
 
@@ -30,14 +62,14 @@ pub fn remove_trailing_dot(domain: &str) -> String {
     domain
 }
 
-fn check_cname(dns_server: &str, domain: &str) -> Option<String> {
-    let client = dns_client(dns_server);
-    let name = Name::from_str(&add_trailing_dot(domain)).ok()?;
-    let response: DnsResponse = client.query(&name, DNSClass::IN, RecordType::CNAME).ok()?;
-    let answers: &[Record] = response.answers();
+async fn check_cname(resolver: &TokioAsyncResolver, domain: &str) -> Option<String> {
+    let response = resolver
+        .lookup(add_trailing_dot(domain), RecordType::CNAME)
+        .await
+        .ok()?;
 
-    for record in answers {
-        if let RData::CNAME(ref cname) = record.rdata() {
+    for record in response.record_iter() {
+        if let Some(RData::CNAME(ref cname)) = record.data() {
             return Some(remove_trailing_dot(&cname.to_utf8()));
         }
     }
@@ -45,11 +77,20 @@ fn check_cname(dns_server: &str, domain: &str) -> Option<String> {
     None
 }
 
-pub fn lookup_real_domain(dns_server: &str, domain: &str) -> String {
+/// Follows CNAME records (at most 10) to the domain the record has to be created at.
+///
+/// `dns_server` is the ip address of a recursive resolver.
+pub async fn lookup_real_domain_async(dns_server: &str, domain: &str) -> String {
+    let mut domain = domain.to_owned();
+
+    let resolver = match resolver(dns_server) {
+        Some(resolver) => resolver,
+        None => return domain,
+    };
+
     let mut depth = 0;
 
-    let mut domain = domain.to_owned();
-    while let Some(real_name) = check_cname(dns_server, &domain) {
+    while let Some(real_name) = check_cname(&resolver, &domain).await {
         debug!("Using {} for {}", real_name, domain);
         domain = real_name;
 
@@ -63,17 +104,19 @@ pub fn lookup_real_domain(dns_server: &str, domain: &str) -> String {
     domain
 }
 
-pub fn check_txt_record(dns_server: &str, domain: &str, value: &str) -> bool {
-    let client = dns_client(dns_server);
-    let name = match Name::from_str(&add_trailing_dot(domain)) {
-        Ok(name) => name,
-        Err(_) => return false,
+/// Checks whether the resolver returns a TXT record with the value for the domain.
+pub async fn check_txt_record_async(dns_server: &str, domain: &str, value: &str) -> bool {
+    let resolver = match resolver(dns_server) {
+        Some(resolver) => resolver,
+        None => return false,
     };
 
-    if let Ok(response) = client.query(&name, DNSClass::IN, RecordType::TXT) {
-        for record in response.answers() {
-            if record.name().to_utf8().to_lowercase() == name.to_utf8().to_lowercase() {
-                if let RData::TXT(data) = record.rdata() {
+    let name = add_trailing_dot(domain).to_lowercase();
+
+    if let Ok(response) = resolver.lookup(name.as_str(), RecordType::TXT).await {
+        for record in response.record_iter() {
+            if record.name().to_utf8().to_lowercase() == name {
+                if let Some(RData::TXT(data)) = record.data() {
                     for data in data.txt_data().iter() {
                         let data = String::from_utf8_lossy(data);
 
@@ -88,3 +131,13 @@ pub fn check_txt_record(dns_server: &str, domain: &str, value: &str) -> bool {
 
     false
 }
+
+/// The blocking version of [`lookup_real_domain_async`].
+pub fn lookup_real_domain(dns_server: &str, domain: &str) -> String {
+    block_on(lookup_real_domain_async(dns_server, domain)).unwrap_or_else(|| domain.to_owned())
+}
+
+/// The blocking version of [`check_txt_record_async`].
+pub fn check_txt_record(dns_server: &str, domain: &str, value: &str) -> bool {
+    block_on(check_txt_record_async(dns_server, domain, value)).unwrap_or(false)
+}
@@ -0,0 +1,274 @@
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+const HEADER: &[u8] = b"letsencrypt-inwx encrypted config v1\n";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const ITERATIONS: usize = 100_000;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EncryptionError {
+    KeyError(PathBuf, io::Error),
+    CryptoError(ErrorStack),
+    /// The data does not have the format of an encrypted config.
+    InvalidFormat,
+    /// The key is wrong or the encrypted data was modified.
+    DecryptionFailed,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptionError::KeyError(path, e) => {
+                write!(f, "Failed to read the key file {}: {}", path.display(), e)
+            }
+            EncryptionError::CryptoError(e) => write!(f, "Encryption error: {}", e),
+            EncryptionError::InvalidFormat => write!(f, "The file is not an encrypted config"),
+            EncryptionError::DecryptionFailed => write!(
+                f,
+                "The decryption failed, the key is wrong or the file was modified"
+            ),
+        }
+    }
+}
+
+impl From<ErrorStack> for EncryptionError {
+    fn from(error: ErrorStack) -> EncryptionError {
+        EncryptionError::CryptoError(error)
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(HEADER)
+}
+
+/// Returns `$LETSENCRYPT_INWX_CONFIG_KEY_FILE`, the credential `letsencrypt-inwx-config.key`
+/// passed by systemd or `/etc/letsencrypt-inwx.key`.
+pub fn find_key_file() -> PathBuf {
+    if let Some(path) = env::var_os("LETSENCRYPT_INWX_CONFIG_KEY_FILE") {
+        return PathBuf::from(path);
+    }
+
+    if let Some(directory) = env::var_os("CREDENTIALS_DIRECTORY") {
+        let path = Path::new(&directory).join("letsencrypt-inwx-config.key");
+
+        if path.is_file() {
+            return path;
+        }
+    }
+
+    PathBuf::from("/etc/letsencrypt-inwx.key")
+}
+
+/// Reads the passphrase of a key file, surrounding whitespace is ignored.
+pub fn read_key(path: &Path) -> Result<Zeroizing<Vec<u8>>, EncryptionError> {
+    let key = Zeroizing::new(
+        fs::read_to_string(path).map_err(|e| EncryptionError::KeyError(path.to_owned(), e))?,
+    );
+
+    Ok(Zeroizing::new(key.trim().as_bytes().to_vec()))
+}
+
+/// Writes a file only readable by its owner, an existing file is replaced at once.
+pub fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+/// Creates a key file with a random passphrase.
+pub fn create_key_file(path: &Path) -> Result<(), EncryptionError> {
+    let mut key = [0; 32];
+    rand_bytes(&mut key)?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let result = options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", base64::encode(&key)));
+
+    result.map_err(|e| EncryptionError::KeyError(path.to_owned(), e))
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, EncryptionError> {
+    let mut key = Zeroizing::new([0; 32]);
+    pbkdf2_hmac(
+        passphrase,
+        salt,
+        ITERATIONS,
+        MessageDigest::sha256(),
+        &mut *key,
+    )?;
+
+    Ok(key)
+}
+
+/// Encrypts the data with aes-256-gcm and a key derived from the passphrase.
+pub fn encrypt(passphrase: &[u8], data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    let mut salt = [0; SALT_LENGTH];
+    let mut nonce = [0; NONCE_LENGTH];
+    let mut tag = [0; TAG_LENGTH];
+    rand_bytes(&mut salt)?;
+    rand_bytes(&mut nonce)?;
+
+    let key = derive_key(passphrase, &salt)?;
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &*key,
+        Some(&nonce),
+        HEADER,
+        data,
+        &mut tag,
+    )?;
+
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&salt);
+    payload.extend_from_slice(&nonce);
+    payload.extend_from_slice(&tag);
+    payload.extend_from_slice(&ciphertext);
+
+    let mut encrypted = HEADER.to_vec();
+    encrypted.extend_from_slice(base64::encode(&payload).as_bytes());
+    encrypted.push(b'\n');
+
+    Ok(encrypted)
+}
+
+pub fn decrypt(passphrase: &[u8], data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if !is_encrypted(data) {
+        return Err(EncryptionError::InvalidFormat);
+    }
+
+    let payload = std::str::from_utf8(&data[HEADER.len()..])
+        .ok()
+        .and_then(|payload| base64::decode(payload.trim()).ok())
+        .filter(|payload| payload.len() >= SALT_LENGTH + NONCE_LENGTH + TAG_LENGTH)
+        .ok_or(EncryptionError::InvalidFormat)?;
+
+    let (salt, payload) = payload.split_at(SALT_LENGTH);
+    let (nonce, payload) = payload.split_at(NONCE_LENGTH);
+    let (tag, ciphertext) = payload.split_at(TAG_LENGTH);
+
+    let key = derive_key(passphrase, salt)?;
+
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &*key,
+        Some(nonce),
+        HEADER,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| EncryptionError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const CONFIG: &[u8] =
+        b"{ \"accounts\": [{ \"username\": \"user\", \"password\": \"secret\" }] }";
+
+    #[test]
+    fn round_trip() {
+        let encrypted = encrypt(b"passphrase", CONFIG).unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(CONFIG));
+        assert!(!encrypted.windows(6).any(|window| window == b"secret"));
+
+        // every encryption uses a new salt and nonce
+        assert_ne!(encrypted, encrypt(b"passphrase", CONFIG).unwrap());
+
+        assert_eq!(decrypt(b"passphrase", &encrypted).unwrap(), CONFIG);
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let encrypted = encrypt(b"passphrase", CONFIG).unwrap();
+
+        assert!(matches!(
+            decrypt(b"wrong", &encrypted),
+            Err(EncryptionError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn modified_data() {
+        let encrypted = encrypt(b"passphrase", CONFIG).unwrap();
+        let mut payload = base64::decode(encrypted[HEADER.len()..].trim_ascii()).unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        let modified = [HEADER, base64::encode(&payload).as_bytes()].concat();
+
+        assert!(matches!(
+            decrypt(b"passphrase", &modified),
+            Err(EncryptionError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            decrypt(b"passphrase", CONFIG),
+            Err(EncryptionError::InvalidFormat)
+        ));
+        assert!(matches!(
+            decrypt(b"passphrase", &encrypted[..HEADER.len() + 8]),
+            Err(EncryptionError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn key_file() {
+        let directory = TempDir::new("encryption-key");
+        let path = directory.join("config.key");
+
+        create_key_file(&path).unwrap();
+        assert!(create_key_file(&path).is_err());
+
+        let key = read_key(&path).unwrap();
+        let encrypted = encrypt(&key, CONFIG).unwrap();
+        assert_eq!(
+            decrypt(&read_key(&path).unwrap(), &encrypted).unwrap(),
+            CONFIG
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
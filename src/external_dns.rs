@@ -0,0 +1,506 @@
+use crate::auth::{authorize, find_token, Client};
+use crate::challenge::{execute_api_commands, Sessions};
+use crate::config::Config;
+use crate::http::{bind, path, read_json, respond_empty, respond_json_as};
+use crate::inwx::{Record, MIN_TTL};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tiny_http::{Method, Request, Server};
+
+const MEDIA_TYPE: &str = "application/external.dns.webhook+json;version=1";
+const SUPPORTED_TYPES: [&str; 7] = ["A", "AAAA", "CNAME", "TXT", "MX", "SRV", "NS"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ProviderSpecific {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Endpoint {
+    dns_name: String,
+    #[serde(default)]
+    targets: Vec<String>,
+    record_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    set_identifier: String,
+    #[serde(rename = "recordTTL", default, skip_serializing_if = "is_zero")]
+    record_ttl: i64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    provider_specific: Vec<ProviderSpecific>,
+}
+
+fn is_zero(value: &i64) -> bool {
+    *value == 0
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+struct Changes {
+    #[serde(default)]
+    create: Option<Vec<Endpoint>>,
+    #[serde(default)]
+    update_old: Option<Vec<Endpoint>>,
+    #[serde(default)]
+    update_new: Option<Vec<Endpoint>>,
+    #[serde(default)]
+    delete: Option<Vec<Endpoint>>,
+}
+
+/// Converts a target of external-dns into the content and priority used by inwx.
+fn target_to_content(record_type: &str, target: &str) -> Result<(String, Option<i32>), ()> {
+    match record_type {
+        "MX" | "SRV" => {
+            let mut parts = target.splitn(2, ' ');
+            let prio = parts.next().and_then(|prio| prio.parse().ok()).ok_or(())?;
+            let content = parts.next().ok_or(())?.trim();
+
+            Ok((content.trim_end_matches('.').to_owned(), Some(prio)))
+        }
+        "CNAME" | "NS" => Ok((target.trim_end_matches('.').to_owned(), None)),
+        _ => Ok((target.to_owned(), None)),
+    }
+}
+
+fn record_to_target(record: &Record) -> String {
+    match record.record_type.as_str() {
+        "MX" | "SRV" => format!("{} {}", record.prio, record.content),
+        _ => record.content.to_owned(),
+    }
+}
+
+struct ExternalDnsServer<'a> {
+    config: &'a Config,
+    sessions: Sessions,
+    domains: Vec<String>,
+    client: Client<'a>,
+}
+
+impl<'a> ExternalDnsServer<'a> {
+    fn in_filter(&self, name: &str) -> bool {
+        self.domains
+            .iter()
+            .any(|domain| name == domain || name.ends_with(&format!(".{}", domain)))
+    }
+
+    fn negotiate(&self, request: Request) {
+        respond_json_as(
+            request,
+            200,
+            MEDIA_TYPE,
+            &json!({ "include": self.domains, "exclude": [] }),
+        );
+    }
+
+    fn records(&mut self, request: Request) {
+        let mut endpoints: Vec<Endpoint> = Vec::new();
+
+        for zone in &self.domains {
+            let mut records = Vec::new();
+
+            let result = execute_api_commands(self.config, &mut self.sessions, zone, |api| {
+                records = api.list_records(zone)?;
+                Ok(())
+            });
+
+            if result.is_err() {
+                return respond_empty(request, 500);
+            }
+
+            for record in records {
+                if !SUPPORTED_TYPES.contains(&record.record_type.as_str()) {
+                    continue;
+                }
+
+                // the nameservers of the zone itself are managed by inwx
+                if record.record_type == "NS" && &record.name == zone {
+                    continue;
+                }
+
+                let target = record_to_target(&record);
+
+                match endpoints.iter_mut().find(|endpoint| {
+                    endpoint.dns_name == record.name && endpoint.record_type == record.record_type
+                }) {
+                    Some(endpoint) => endpoint.targets.push(target),
+                    None => endpoints.push(Endpoint {
+                        dns_name: record.name,
+                        targets: vec![target],
+                        record_type: record.record_type,
+                        set_identifier: String::new(),
+                        record_ttl: i64::from(record.ttl),
+                        labels: BTreeMap::new(),
+                        provider_specific: vec![],
+                    }),
+                }
+            }
+        }
+
+        respond_json_as(request, 200, MEDIA_TYPE, &endpoints);
+    }
+
+    fn delete_endpoint(&mut self, endpoint: &Endpoint) -> Result<(), ()> {
+        for target in &endpoint.targets {
+            let (content, _) = target_to_content(&endpoint.record_type, target)
+                .map_err(|_| error!("Invalid {} target {}", endpoint.record_type, target))?;
+
+            info!(
+                "Deleting {} record {} {}",
+                endpoint.record_type, endpoint.dns_name, target
+            );
+
+            execute_api_commands(self.config, &mut self.sessions, &endpoint.dns_name, |api| {
+                let records = api.get_records(
+                    &endpoint.dns_name,
+                    Some(&endpoint.record_type),
+                    Some(&content),
+                )?;
+
+                for record in records {
+                    api.delete_record(record.id)?;
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn create_endpoint(&mut self, endpoint: &Endpoint) -> Result<(), ()> {
+        let ttl = match endpoint.record_ttl {
+            0 => None,
+            ttl => Some(ttl.max(i64::from(MIN_TTL)) as i32),
+        };
+
+        for target in &endpoint.targets {
+            let (content, prio) = target_to_content(&endpoint.record_type, target)
+                .map_err(|_| error!("Invalid {} target {}", endpoint.record_type, target))?;
+
+            info!(
+                "Creating {} record {} {}",
+                endpoint.record_type, endpoint.dns_name, target
+            );
+
+            execute_api_commands(self.config, &mut self.sessions, &endpoint.dns_name, |api| {
+                api.create_record(
+                    &endpoint.dns_name,
+                    &endpoint.record_type,
+                    &content,
+                    ttl,
+                    prio,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_changes(&mut self, mut request: Request) {
+        let changes: Changes = match read_json(&mut request) {
+            Some(changes) => changes,
+            None => return respond_empty(request, 400),
+        };
+
+        // updates replace all targets, so the old records are deleted before the new ones are created
+        let deletions = changes
+            .delete
+            .into_iter()
+            .chain(changes.update_old)
+            .flatten();
+        let creations = changes
+            .update_new
+            .into_iter()
+            .chain(changes.create)
+            .flatten();
+
+        let changes: Vec<(Endpoint, bool)> = deletions
+            .map(|endpoint| (endpoint, false))
+            .chain(creations.map(|endpoint| (endpoint, true)))
+            .collect();
+
+        // reject the whole batch before anything has been changed
+        if let Some((endpoint, _)) = changes
+            .iter()
+            .find(|(endpoint, _)| !self.in_filter(&endpoint.dns_name))
+        {
+            warn!("{} is not part of the domain filter", endpoint.dns_name);
+            return respond_empty(request, 400);
+        }
+
+        for (endpoint, _) in &changes {
+            for target in &endpoint.targets {
+                if target_to_content(&endpoint.record_type, target).is_err() {
+                    warn!("Invalid {} target {}", endpoint.record_type, target);
+                    return respond_empty(request, 400);
+                }
+            }
+        }
+
+        for (endpoint, create) in &changes {
+            let action = match create {
+                true => "create",
+                false => "delete",
+            };
+
+            if !authorize(
+                self.config,
+                &self.client,
+                action,
+                &endpoint.dns_name,
+                &endpoint.record_type,
+            ) {
+                return respond_empty(request, 403);
+            }
+        }
+
+        // the changes are not atomic, external-dns applies the remaining ones with its next sync
+        for (endpoint, create) in changes {
+            let result = match create {
+                true => self.create_endpoint(&endpoint),
+                false => self.delete_endpoint(&endpoint),
+            };
+
+            if result.is_err() {
+                return respond_empty(request, 500);
+            }
+        }
+
+        respond_empty(request, 204);
+    }
+
+    fn adjust_endpoints(&self, mut request: Request) {
+        let mut endpoints: Vec<Endpoint> = match read_json(&mut request) {
+            Some(endpoints) => endpoints,
+            None => return respond_empty(request, 400),
+        };
+
+        for endpoint in &mut endpoints {
+            endpoint.dns_name = endpoint.dns_name.trim_end_matches('.').to_owned();
+
+            if endpoint.record_ttl != 0 && endpoint.record_ttl < i64::from(MIN_TTL) {
+                endpoint.record_ttl = i64::from(MIN_TTL);
+            }
+        }
+
+        respond_json_as(request, 200, MEDIA_TYPE, &endpoints);
+    }
+}
+
+/// Serves the external-dns webhook provider api for the domains of the configured accounts.
+pub fn serve(config: &Config) -> Result<(), ()> {
+    let mut domains: Vec<String> = config
+        .accounts
+        .iter()
+        .flat_map(|account| account.domains.iter().cloned())
+        .collect();
+    domains.sort();
+    domains.dedup();
+
+    if domains.is_empty() {
+        error!("The domains of the accounts have to be configured for external-dns");
+        return Err(());
+    }
+
+    // external-dns can't authenticate itself, so the scope of the server is configured instead
+    let client = match config.external_dns.token {
+        Some(ref name) => Client::Token(find_token(config, name).ok_or_else(|| {
+            error!("The token {} is not configured", name);
+        })?),
+        None => Client::Unrestricted("external-dns"),
+    };
+
+    let listener = bind(&config.external_dns.listen)?;
+
+    handle_requests(config, domains, client, &listener);
+
+    Ok(())
+}
+
+fn handle_requests(config: &Config, domains: Vec<String>, client: Client, listener: &Server) {
+    let mut server = ExternalDnsServer {
+        config,
+        sessions: Sessions::new(),
+        domains,
+        client,
+    };
+
+    for request in listener.incoming_requests() {
+        debug!("{} {}", request.method(), request.url());
+
+        match (request.method(), path(&request)) {
+            (Method::Get, "/") => server.negotiate(request),
+            (Method::Get, "/records") => server.records(request),
+            (Method::Post, "/records") => server.apply_changes(request),
+            (Method::Post, "/adjustendpoints") => server.adjust_endpoints(request),
+            (Method::Get, "/healthz") => respond_empty(request, 200),
+            _ => respond_empty(request, 404),
+        }
+    }
+
+    server.sessions.logout();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, client, spawn_server, TempDir};
+    use serde_json::Value;
+    use std::fs;
+
+    /// Starts the server for example.com with the records in a file of a new directory.
+    fn start(name: &str, token: Option<&str>) -> (String, TempDir) {
+        let directory = TempDir::new(&format!("external-dns-{}", name));
+
+        let config = test_util::config(json!({
+            "records_file": {
+                "path": directory.join("records.json"),
+                "zones": ["example.com"],
+            },
+            "tokens": [
+                {
+                    "name": "acme",
+                    "secret": "secret",
+                    "names": ["_acme-challenge.*"],
+                },
+            ],
+        }));
+
+        let client = match token {
+            Some(name) => Client::Token(find_token(config, name).unwrap()),
+            None => Client::Unrestricted("external-dns"),
+        };
+
+        let url = spawn_server(move |server| {
+            handle_requests(config, vec!["example.com".to_owned()], client, &server)
+        });
+
+        (url, directory)
+    }
+
+    fn records(directory: &TempDir) -> Vec<Value> {
+        serde_json::from_slice(&fs::read(directory.join("records.json")).unwrap()).unwrap()
+    }
+
+    fn apply(url: &str, changes: Value) -> u16 {
+        client()
+            .post(format!("{}/records", url))
+            .json(&changes)
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+    }
+
+    #[test]
+    fn negotiate() {
+        let (url, _directory) = start("negotiate", None);
+
+        let response = client().get(&url).send().unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["Content-Type"], MEDIA_TYPE);
+
+        let filter: Value = response.json().unwrap();
+        assert_eq!(filter, json!({ "include": ["example.com"], "exclude": [] }));
+    }
+
+    #[test]
+    fn create_and_update() {
+        let (url, directory) = start("update", None);
+
+        let mail = json!({
+            "dnsName": "example.com",
+            "targets": ["10 mail.example.com."],
+            "recordType": "MX",
+            "recordTTL": 3600,
+        });
+        let old =
+            json!({ "dnsName": "www.example.com", "targets": ["192.0.2.1"], "recordType": "A" });
+        let new =
+            json!({ "dnsName": "www.example.com", "targets": ["192.0.2.2"], "recordType": "A" });
+
+        assert_eq!(apply(&url, json!({ "Create": [mail, old] })), 204);
+        assert_eq!(
+            apply(&url, json!({ "UpdateOld": [old], "UpdateNew": [new] })),
+            204
+        );
+
+        let created = records(&directory);
+        assert_eq!(created.len(), 2);
+        assert_eq!(created[0]["content"], "mail.example.com");
+        assert_eq!(created[0]["prio"], 10);
+        assert_eq!(created[0]["ttl"], 3600);
+        assert_eq!(created[1]["name"], "www.example.com");
+        assert_eq!(created[1]["content"], "192.0.2.2");
+
+        let endpoints: Value = client()
+            .get(format!("{}/records", url))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(endpoints[0]["dnsName"], "example.com");
+        assert_eq!(endpoints[0]["targets"], json!(["10 mail.example.com"]));
+        assert_eq!(endpoints[1]["dnsName"], "www.example.com");
+        assert_eq!(endpoints[1]["targets"], json!(["192.0.2.2"]));
+    }
+
+    #[test]
+    fn rejects_invalid_changes() {
+        let (url, directory) = start("invalid", None);
+
+        let valid =
+            json!({ "dnsName": "www.example.com", "targets": ["192.0.2.1"], "recordType": "A" });
+        let invalid = json!({ "dnsName": "example.com", "targets": ["mail.example.com"], "recordType": "MX" });
+        let foreign =
+            json!({ "dnsName": "www.example.org", "targets": ["192.0.2.1"], "recordType": "A" });
+
+        // nothing is changed if one of the changes is invalid
+        assert_eq!(apply(&url, json!({ "Create": [valid, invalid] })), 400);
+        assert_eq!(apply(&url, json!({ "Create": [valid, foreign] })), 400);
+
+        assert!(!directory.join("records.json").exists());
+    }
+
+    #[test]
+    fn token_scope() {
+        let (url, directory) = start("token", Some("acme"));
+
+        let challenge = json!({
+            "dnsName": "_acme-challenge.example.com",
+            "targets": ["\"value\""],
+            "recordType": "TXT",
+        });
+        let address =
+            json!({ "dnsName": "www.example.com", "targets": ["192.0.2.1"], "recordType": "A" });
+
+        assert_eq!(apply(&url, json!({ "Create": [challenge, address] })), 403);
+        assert!(!directory.join("records.json").exists());
+
+        assert_eq!(apply(&url, json!({ "Create": [challenge] })), 204);
+        assert_eq!(records(&directory).len(), 1);
+    }
+
+    #[test]
+    fn adjust_endpoints() {
+        let (url, _directory) = start("adjust", None);
+
+        let endpoints: Value = client()
+            .post(format!("{}/adjustendpoints", url))
+            .json(&json!([
+                { "dnsName": "www.example.com.", "targets": ["192.0.2.1"], "recordType": "A", "recordTTL": 60 },
+                { "dnsName": "example.com", "targets": ["192.0.2.1"], "recordType": "A" },
+            ]))
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+
+        assert_eq!(endpoints[0]["dnsName"], "www.example.com");
+        assert_eq!(endpoints[0]["recordTTL"], MIN_TTL);
+        assert!(endpoints[1].get("recordTTL").is_none());
+    }
+}
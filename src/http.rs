@@ -0,0 +1,178 @@
+use reqwest::{NoProxy, Proxy};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::io::Read;
+use std::net::IpAddr;
+use tiny_http::{Header, Request, Response, Server};
+
+// request bodies of the supported apis are tiny, anything larger is rejected
+pub const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+pub fn bind(listen: &str) -> Result<Server, ()> {
+    let server =
+        Server::http(listen).map_err(|e| error!("Failed to listen on {}: {}", listen, e))?;
+
+    info!("Listening on {}", listen);
+
+    Ok(server)
+}
+
+fn env_proxy(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Returns the proxies of a http client, `None` keeps the default of reqwest (the proxies and
+/// `NO_PROXY` of the environment) and an empty list disables the proxies.
+///
+/// Without `proxy` the proxies of the environment are used, `no_proxy` falls back to the
+/// `NO_PROXY` variable and `*` disables the proxies.
+pub fn proxies(proxy: Option<&str>, no_proxy: Option<&str>) -> reqwest::Result<Option<Vec<Proxy>>> {
+    if proxy.is_none() && no_proxy.is_none() {
+        return Ok(None);
+    }
+
+    // reqwest only applies a wildcard to domain names, not to ip addresses
+    if no_proxy == Some("*") {
+        return Ok(Some(vec![]));
+    }
+
+    let no_proxy = match no_proxy {
+        Some(no_proxy) => NoProxy::from_string(no_proxy),
+        None => NoProxy::from_env(),
+    };
+
+    let mut proxies = Vec::new();
+
+    match proxy {
+        Some(url) => proxies.push(Proxy::all(url)?),
+        None => {
+            // the precedence of the variables is the same as the one of reqwest
+            if let Some(url) = env_proxy(&["HTTPS_PROXY", "https_proxy"]) {
+                proxies.push(Proxy::https(&url)?);
+            }
+            if let Some(url) = env_proxy(&["HTTP_PROXY", "http_proxy"]) {
+                proxies.push(Proxy::http(&url)?);
+            }
+            if let Some(url) = env_proxy(&["ALL_PROXY", "all_proxy"]) {
+                proxies.push(Proxy::all(&url)?);
+            }
+        }
+    }
+
+    Ok(Some(
+        proxies
+            .into_iter()
+            .map(|proxy| proxy.no_proxy(no_proxy.clone()))
+            .collect(),
+    ))
+}
+
+pub fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+/// Returns the path of the request url without the query string.
+pub fn path(request: &Request) -> &str {
+    request.url().split('?').next().unwrap_or("")
+}
+
+pub fn read_json<T: DeserializeOwned>(request: &mut Request) -> Option<T> {
+    let mut body = Vec::new();
+
+    request
+        .as_reader()
+        .take(MAX_BODY_SIZE)
+        .read_to_end(&mut body)
+        .ok()?;
+
+    serde_json::from_slice(&body).ok()
+}
+
+pub fn respond_json<T: Serialize>(request: Request, status: u16, body: &T) {
+    respond_json_as(request, status, "application/json", body)
+}
+
+/// Responds with a json body and a vendor specific content type.
+pub fn respond_json_as<T: Serialize>(request: Request, status: u16, content_type: &str, body: &T) {
+    let body = serde_json::to_vec(body).unwrap();
+    let response = Response::from_data(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to send response: {}", e);
+    }
+}
+
+pub fn respond_empty(request: Request, status: u16) {
+    if let Err(e) = request.respond(Response::empty(status)) {
+        warn!("Failed to send response: {}", e);
+    }
+}
+
+fn ip_to_bits(ip: IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+/// Checks whether the address is part of the network in CIDR notation (i.e. "192.168.0.0/16").
+/// Returns `None` if the network is invalid.
+pub fn in_network(ip: IpAddr, network: &str) -> Option<bool> {
+    let mut parts = network.splitn(2, '/');
+    let network: IpAddr = parts.next()?.parse().ok()?;
+    let (network, bits) = ip_to_bits(network);
+    let prefix = match parts.next() {
+        Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+        None => bits,
+    };
+
+    let ip = match ip {
+        IpAddr::V6(v6) if bits == 32 => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        _ => ip,
+    };
+    let (ip, ip_bits) = ip_to_bits(ip);
+
+    if ip_bits != bits {
+        return Some(false);
+    }
+
+    let mask = match prefix {
+        0 => 0,
+        prefix => u128::MAX << (bits - prefix) & (u128::MAX >> (128 - bits)),
+    };
+
+    Some(ip & mask == network & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_defaults() {
+        assert!(proxies(None, None).unwrap().is_none());
+        assert!(proxies(None, Some("*")).unwrap().unwrap().is_empty());
+        assert!(proxies(Some("http://proxy:3128"), Some("*"))
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            proxies(Some("http://proxy:3128"), Some("localhost"))
+                .unwrap()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(proxies(Some("not a url"), None).is_err());
+    }
+}
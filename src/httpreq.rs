@@ -0,0 +1,149 @@
+use crate::acme::{challenge_name, challenge_value};
+use crate::auth::{authenticate, authorize, basic_auth_matches, Client};
+use crate::challenge::{create_record, delete, Sessions};
+use crate::config::Config;
+use crate::dns::{lookup_real_domain, remove_trailing_dot};
+use crate::http::{bind, path, read_json, respond_empty, respond_json};
+use serde::Deserialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response};
+
+/// The body of lego's httpreq provider, the RAW mode sends the unprocessed challenge.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChallengeRequest {
+    Default {
+        fqdn: String,
+        value: String,
+    },
+    // the token is ignored, the record value is derived from the key authorization
+    #[serde(rename_all = "camelCase")]
+    Raw {
+        domain: String,
+        key_auth: String,
+    },
+}
+
+struct HttpreqServer<'a> {
+    config: &'a Config,
+    sessions: Sessions,
+}
+
+impl<'a> HttpreqServer<'a> {
+    fn client(&self, request: &Request) -> Option<Client<'a>> {
+        if let Some(token) = authenticate(self.config, request) {
+            return Some(Client::Token(token));
+        }
+
+        match (&self.config.httpreq.username, &self.config.httpreq.password) {
+            (Some(username), Some(password)) => {
+                match basic_auth_matches(request, username, password.expose()) {
+                    true => Some(Client::Unrestricted(username)),
+                    false => None,
+                }
+            }
+            _ if self.config.tokens.is_empty() => Some(Client::Unrestricted("anonymous")),
+            _ => None,
+        }
+    }
+
+    fn challenge(&self, body: ChallengeRequest) -> Result<(String, String), ()> {
+        let (name, value) = match body {
+            ChallengeRequest::Default { fqdn, value } => (remove_trailing_dot(&fqdn), value),
+            ChallengeRequest::Raw { domain, key_auth } => {
+                let value = challenge_value(&key_auth)
+                    .map_err(|e| error!("Failed to compute the record value: {}", e))?;
+
+                (challenge_name(&domain), value)
+            }
+        };
+
+        Ok((
+            lookup_real_domain(&self.config.options_for(&name).dns_server, &name),
+            value,
+        ))
+    }
+
+    fn handle(&mut self, mut request: Request, present: bool) {
+        let client = match self.client(&request) {
+            Some(client) => client,
+            None => {
+                let response = Response::empty(401).with_header(
+                    Header::from_bytes("WWW-Authenticate", "Basic realm=\"letsencrypt-inwx\"")
+                        .unwrap(),
+                );
+
+                if let Err(e) = request.respond(response) {
+                    warn!("Failed to send response: {}", e);
+                }
+
+                return;
+            }
+        };
+
+        let body: ChallengeRequest = match read_json(&mut request) {
+            Some(body) => body,
+            None => return respond_json(request, 400, &json!({ "error": "malformed_json" })),
+        };
+
+        let (domain, value) = match self.challenge(body) {
+            Ok(challenge) => challenge,
+            Err(_) => return respond_json(request, 400, &json!({ "error": "bad_challenge" })),
+        };
+
+        let action = match present {
+            true => "present",
+            false => "cleanup",
+        };
+
+        if !authorize(self.config, &client, action, &domain, "TXT") {
+            return respond_json(request, 403, &json!({ "error": "forbidden" }));
+        }
+
+        // lego checks the dns itself after presenting, waiting here would block the other
+        // requests and exceed lego's http timeout
+        let result = match present {
+            true => create_record(self.config, &mut self.sessions, &domain, &value).map(|_| ()),
+            false => delete(self.config, &mut self.sessions, &domain, Some(&value)),
+        };
+
+        match result {
+            Ok(_) => respond_empty(request, 200),
+            Err(_) => respond_json(request, 500, &json!({ "error": "update_failed" })),
+        }
+    }
+}
+
+/// Serves the api of lego's httpreq provider, so clients don't need the inwx credentials.
+pub fn serve(config: &Config) -> Result<(), ()> {
+    if config.httpreq.username.is_some() != config.httpreq.password.is_some() {
+        error!("httpreq.username and httpreq.password have to be configured together");
+        return Err(());
+    }
+
+    if config.httpreq.username.is_none() && config.tokens.is_empty() {
+        warn!("No credentials configured, every client can create records");
+    }
+
+    let mut server = HttpreqServer {
+        config,
+        sessions: Sessions::new(),
+    };
+
+    let listener = bind(&config.httpreq.listen)?;
+
+    for request in listener.incoming_requests() {
+        debug!("{} {}", request.method(), request.url());
+
+        match (request.method(), path(&request)) {
+            (Method::Post, "/present") => server.handle(request, true),
+            (Method::Post, "/cleanup") => server.handle(request, false),
+            (Method::Get, "/health") => respond_empty(request, 200),
+            _ => respond_empty(request, 404),
+        }
+    }
+
+    server.sessions.logout();
+
+    Ok(())
+}
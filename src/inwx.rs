@@ -1,29 +1,65 @@
 use super::config::Account;
+use super::http;
 use super::rpc::{
     RpcError, RpcRequest, RpcRequestParameter, RpcRequestParameterValue, RpcResponse,
 };
 use cookie::CookieJar;
+use reqwest::{Certificate, Client};
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::fs;
 use sxd_xpath::{evaluate_xpath, Value};
+use tokio::runtime::{self, Handle, Runtime};
 
 const API_URL: &str = "https://api.domrobot.com/xmlrpc/";
 const OTE_API_URL: &str = "https://api.ote.domrobot.com/xmlrpc/";
 
+// "Command use error" and "Authentication error" are returned once the session has expired
+const SESSION_EXPIRED_CODES: [i32; 2] = [2002, 2200];
+
+/// The lowest ttl accepted by inwx.
+pub const MIN_TTL: i32 = 300;
+
+const RECORD_XPATH: &str = "/methodResponse/params/param/value/struct/member[name/text()=\"resData\"]/value/struct/member[name/text()=\"record\"]/value/array/data/value";
+
+/// A record as returned by the inwx api.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub id: i32,
+    /// The fully qualified name without a trailing dot.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    /// The content without the priority, i.e. `mail.example.com` for a MX record.
+    pub content: String,
+    pub ttl: i32,
+    /// The priority of MX and SRV records.
+    pub prio: i32,
+}
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum InwxError {
     RpcError(RpcError),
+    /// None of the zones of the account contains the domain.
     DomainNotFound,
     RecordNotFound,
+    /// The ca bundle of the account could not be loaded.
+    CaBundleError(String),
+    /// The runtime of the blocking [`Inwx`] could not be created.
+    RuntimeError(String),
 }
 
 impl fmt::Display for InwxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &InwxError::RpcError(ref e) => write!(f, "An inwx api call failed: {}", e),
-            &InwxError::DomainNotFound => {
+            InwxError::RpcError(e) => write!(f, "An inwx api call failed: {}", e),
+            InwxError::DomainNotFound => {
                 write!(f, "There is no nameserver for the specified domain")
             }
-            &InwxError::RecordNotFound => write!(f, "The specified record does not exist"),
+            InwxError::RecordNotFound => write!(f, "The specified record does not exist"),
+            InwxError::CaBundleError(e) => write!(f, "Failed to load the ca bundle: {}", e),
+            InwxError::RuntimeError(e) => write!(f, "Failed to create the tokio runtime: {}", e),
         }
     }
 }
@@ -34,23 +70,96 @@ impl From<RpcError> for InwxError {
     }
 }
 
-pub struct Inwx<'a> {
+fn client(account: &Account) -> Result<Client, InwxError> {
+    let mut builder = Client::builder();
+    let mut certificates = Vec::new();
+
+    if let Some(ref path) = account.ca_bundle {
+        let pem =
+            fs::read(path).map_err(|e| InwxError::CaBundleError(format!("{}: {}", path, e)))?;
+
+        certificates = Certificate::from_pem_bundle(&pem)
+            .map_err(|e| InwxError::CaBundleError(format!("{}: {}", path, e)))?;
+    }
+
+    if account.pin_ca_bundle && certificates.is_empty() {
+        return Err(InwxError::CaBundleError(
+            "pin_ca_bundle is set, but the ca bundle contains no certificates".to_owned(),
+        ));
+    }
+
+    for certificate in certificates {
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    let proxies = http::proxies(account.proxy.as_deref(), account.no_proxy.as_deref())
+        .map_err(|e| InwxError::RpcError(e.into()))?;
+
+    if let Some(proxies) = proxies {
+        builder = builder.no_proxy();
+
+        for proxy in proxies {
+            builder = builder.proxy(proxy);
+        }
+    }
+
+    builder
+        .tls_built_in_root_certs(!account.pin_ca_bundle)
+        .build()
+        .map_err(|e| InwxError::RpcError(e.into()))
+}
+
+/// A logged in session of an inwx account.
+pub struct AsyncInwx {
+    client: Client,
     cookies: CookieJar,
-    account: &'a Account,
+    account: Account,
+    logged_in: bool,
 }
 
-impl<'a> Inwx<'a> {
-    fn send_request(&mut self, request: RpcRequest) -> Result<RpcResponse, InwxError> {
-        let url = match self.account.ote {
-            true => OTE_API_URL,
-            false => API_URL,
-        };
-        let response = request.send(url, &mut self.cookies)?;
+impl AsyncInwx {
+    fn url(&self) -> String {
+        match (&self.account.api_url, self.account.ote) {
+            (Some(url), _) => url.to_owned(),
+            (None, true) => OTE_API_URL.to_owned(),
+            (None, false) => API_URL.to_owned(),
+        }
+    }
+
+    async fn send_request(&mut self, request: RpcRequest) -> Result<RpcResponse, InwxError> {
+        let url = self.url();
+
+        // the xml document of a response isn't Send, so its scope has to end before the next await
+        {
+            let response = request
+                .clone()
+                .send(&self.client, &url, &mut self.cookies)
+                .await;
+
+            let expired = match response {
+                Err(ref e) => e
+                    .code()
+                    .is_some_and(|code| self.logged_in && SESSION_EXPIRED_CODES.contains(&code)),
+                Ok(_) => false,
+            };
+
+            if !expired {
+                return Ok(response?);
+            }
+        }
+
+        debug!(
+            "The session of account {} expired, logging in again",
+            self.account.username
+        );
+
+        self.cookies = CookieJar::new();
+        self.login().await?;
 
-        Ok(response)
+        Ok(request.send(&self.client, &url, &mut self.cookies).await?)
     }
 
-    fn login(&mut self) -> Result<(), InwxError> {
+    async fn login(&mut self) -> Result<(), InwxError> {
         let request = RpcRequest::new(
             "account.login",
             &[
@@ -60,33 +169,44 @@ impl<'a> Inwx<'a> {
                 },
                 RpcRequestParameter {
                     name: "pass",
-                    value: RpcRequestParameterValue::String(self.account.password.to_owned()),
+                    value: RpcRequestParameterValue::String(
+                        self.account.password.expose().to_owned(),
+                    ),
                 },
             ],
         );
 
         debug!("Logging into account {}", self.account.username);
 
-        self.send_request(request)?;
+        self.logged_in = false;
+        request
+            .send(&self.client, &self.url(), &mut self.cookies)
+            .await?;
+        self.logged_in = true;
 
         Ok(())
     }
 
-    pub fn new(account: &'a Account) -> Result<Inwx<'a>, InwxError> {
-        let mut api = Inwx {
+    /// Logs into the account, the ote (test environment) is used if `account.ote` is set and
+    /// `account.api_url` overrides both.
+    pub async fn new(account: &Account) -> Result<AsyncInwx, InwxError> {
+        let mut api = AsyncInwx {
+            client: client(account)?,
             cookies: CookieJar::new(),
-            account,
+            account: account.to_owned(),
+            logged_in: false,
         };
 
-        api.login()?;
+        api.login().await?;
 
         Ok(api)
     }
 
-    fn split_domain(&mut self, domain: &str) -> Result<(String, String), InwxError> {
-        debug!("Splitting domain {}", domain);
+    /// Returns the names of all zones hosted by inwx for this account.
+    pub async fn list_zones(&mut self) -> Result<Vec<String>, InwxError> {
         let page_size = 20;
         let mut page = 1;
+        let mut zones = Vec::new();
 
         loop {
             debug!("Requesting page {} of nameserver.list", page);
@@ -104,7 +224,7 @@ impl<'a> Inwx<'a> {
                 ],
             );
 
-            let response = self.send_request(request)?;
+            let response = self.send_request(request).await?;
 
             let total: i32 = evaluate_xpath(
                 &response.get_document(),
@@ -115,23 +235,9 @@ impl<'a> Inwx<'a> {
                 .ok_or_else(|| InwxError::DomainNotFound)?;
 
             if let Ok(Value::Nodeset(ref nodes)) = evaluate_xpath(&response.get_document(), "/methodResponse/params/param/value/struct/member[name/text()=\"resData\"]/value/struct/member[name/text()=\"domains\"]/value/array/data/value/struct/member[name/text()=\"domain\"]/value/string/text()") {
-                for node in nodes {
+                for node in nodes.document_order() {
                     if let Some(ref text) = node.text() {
-                        let domain_root = text.text();
-                        debug!("Checking domain {}", domain_root);
-
-                        if domain.ends_with(&format!(".{}", domain_root)) {
-                            let name = &domain[0..domain.len() - domain_root.len() - 1];
-                            debug!("Found domain root {}", domain_root);
-
-                            return Ok((domain_root.to_owned(), name.to_owned()));
-                        } else if domain == domain_root {
-                            debug!("Found domain root {}", domain_root);
-
-                            return Ok((domain_root.to_owned(), "".to_owned()));
-                        }
-
-                        debug!("{} is not the domain root of {}", domain_root, domain);
+                        zones.push(text.text().to_owned());
                     }
                 }
             }
@@ -139,75 +245,261 @@ impl<'a> Inwx<'a> {
             if total > page * page_size {
                 page += 1;
             } else {
-                return Err(InwxError::DomainNotFound);
+                return Ok(zones);
             }
         }
     }
 
-    pub fn create_txt_record(&mut self, domain: &str, content: &str) -> Result<(), InwxError> {
-        let (domain, name) = self.split_domain(domain)?;
+    /// Splits the domain into the zone hosted by inwx and the name of the record within that zone.
+    pub async fn split_domain(&mut self, domain: &str) -> Result<(String, String), InwxError> {
+        debug!("Splitting domain {}", domain);
 
-        let request = RpcRequest::new(
-            "nameserver.createRecord",
-            &[
-                RpcRequestParameter {
-                    name: "type",
-                    value: RpcRequestParameterValue::String("TXT".to_owned()),
-                },
-                RpcRequestParameter {
-                    name: "name",
-                    value: RpcRequestParameterValue::String(name),
-                },
-                RpcRequestParameter {
-                    name: "content",
-                    value: RpcRequestParameterValue::String(content.to_owned()),
-                },
-                RpcRequestParameter {
-                    name: "domain",
-                    value: RpcRequestParameterValue::String(domain),
-                },
-            ],
-        );
+        // the most specific zone wins if zones are nested
+        let domain_root = self
+            .list_zones()
+            .await?
+            .into_iter()
+            .filter(|domain_root| {
+                domain == domain_root || domain.ends_with(&format!(".{}", domain_root))
+            })
+            .max_by_key(|domain_root| domain_root.len());
+
+        if let Some(domain_root) = domain_root {
+            debug!("Found domain root {}", domain_root);
+            let name = domain[..domain.len() - domain_root.len()].trim_end_matches('.');
+
+            return Ok((domain_root, name.to_owned()));
+        }
+
+        Err(InwxError::DomainNotFound)
+    }
+
+    /// Creates a record, the content has the format of the inwx api (i.e. without the priority of MX records).
+    pub async fn create_record(
+        &mut self,
+        domain: &str,
+        record_type: &str,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), InwxError> {
+        let (domain, name) = self.split_domain(domain).await?;
+
+        let mut parameters = vec![
+            RpcRequestParameter {
+                name: "type",
+                value: RpcRequestParameterValue::String(record_type.to_owned()),
+            },
+            RpcRequestParameter {
+                name: "name",
+                value: RpcRequestParameterValue::String(name),
+            },
+            RpcRequestParameter {
+                name: "content",
+                value: RpcRequestParameterValue::String(content.to_owned()),
+            },
+            RpcRequestParameter {
+                name: "domain",
+                value: RpcRequestParameterValue::String(domain),
+            },
+        ];
+
+        if let Some(ttl) = ttl {
+            parameters.push(RpcRequestParameter {
+                name: "ttl",
+                value: RpcRequestParameterValue::Int(ttl),
+            });
+        }
 
-        self.send_request(request)?;
+        if let Some(prio) = prio {
+            parameters.push(RpcRequestParameter {
+                name: "prio",
+                value: RpcRequestParameterValue::Int(prio),
+            });
+        }
+
+        let request = RpcRequest::new("nameserver.createRecord", &parameters);
+
+        self.send_request(request).await?;
 
         Ok(())
     }
 
-    pub fn get_record_id(&mut self, domain: &str) -> Result<i32, InwxError> {
-        let (domain, name) = self.split_domain(domain)?;
+    /// Creates a TXT record with the default ttl.
+    pub async fn create_txt_record(
+        &mut self,
+        domain: &str,
+        content: &str,
+    ) -> Result<(), InwxError> {
+        self.create_record(domain, "TXT", content, None, None).await
+    }
 
-        let request = RpcRequest::new(
-            "nameserver.info",
-            &[
-                RpcRequestParameter {
-                    name: "type",
-                    value: RpcRequestParameterValue::String("TXT".to_owned()),
-                },
-                RpcRequestParameter {
-                    name: "name",
-                    value: RpcRequestParameterValue::String(name.to_owned()),
-                },
-                RpcRequestParameter {
-                    name: "domain",
-                    value: RpcRequestParameterValue::String(domain.to_owned()),
-                },
-            ],
-        );
+    async fn query_records(
+        &mut self,
+        zone: &str,
+        name: Option<&str>,
+        record_type: Option<&str>,
+        content: Option<&str>,
+    ) -> Result<Vec<Record>, InwxError> {
+        let mut parameters = vec![RpcRequestParameter {
+            name: "domain",
+            value: RpcRequestParameterValue::String(zone.to_owned()),
+        }];
+
+        if let Some(name) = name {
+            parameters.push(RpcRequestParameter {
+                name: "name",
+                value: RpcRequestParameterValue::String(name.to_owned()),
+            });
+        }
 
-        let response = self.send_request(request)?;
+        if let Some(record_type) = record_type {
+            parameters.push(RpcRequestParameter {
+                name: "type",
+                value: RpcRequestParameterValue::String(record_type.to_owned()),
+            });
+        }
 
-        let id = match evaluate_xpath(&response.get_document(), "/methodResponse/params/param/value/struct/member[name/text()=\"resData\"]/value/struct/member[name/text()=\"record\"]/value/array/data/value[1]/struct/member[name/text()=\"id\"]/value/int/text()") {
-            Ok(ref id) => id.string().parse::<i32>().ok(),
-            Err(_) => None
+        if let Some(content) = content {
+            parameters.push(RpcRequestParameter {
+                name: "content",
+                value: RpcRequestParameterValue::String(content.to_owned()),
+            });
+        }
+
+        let request = RpcRequest::new("nameserver.info", &parameters);
+
+        let response = self.send_request(request).await?;
+        let document = response.get_document();
+
+        let count = match evaluate_xpath(&document, &format!("count({})", RECORD_XPATH)) {
+            Ok(Value::Number(count)) => count as usize,
+            _ => 0,
         };
 
-        id.ok_or_else(|| InwxError::RecordNotFound)
+        let member = |index: usize, member: &str| {
+            evaluate_xpath(
+                &document,
+                &format!(
+                    "{}[{}]/struct/member[name/text()=\"{}\"]/value//text()",
+                    RECORD_XPATH, index, member
+                ),
+            )
+            .map(|value| value.string())
+            .unwrap_or_default()
+        };
+
+        let mut records = Vec::new();
+
+        for index in 1..=count {
+            let id = match member(index, "id").parse() {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            // the api returns fully qualified names, but don't rely on it
+            let name = member(index, "name");
+            let name = if name.is_empty() {
+                zone.to_owned()
+            } else if name == zone || name.ends_with(&format!(".{}", zone)) {
+                name
+            } else {
+                format!("{}.{}", name, zone)
+            };
+
+            records.push(Record {
+                id,
+                name,
+                record_type: member(index, "type"),
+                content: member(index, "content"),
+                ttl: member(index, "ttl").parse().unwrap_or(0),
+                prio: member(index, "prio").parse().unwrap_or(0),
+            });
+        }
+
+        Ok(records)
+    }
+
+    /// Returns the records of the domain, `record_type` and `content` narrow the result down.
+    pub async fn get_records(
+        &mut self,
+        domain: &str,
+        record_type: Option<&str>,
+        content: Option<&str>,
+    ) -> Result<Vec<Record>, InwxError> {
+        let (zone, name) = self.split_domain(domain).await?;
+
+        let records = self
+            .query_records(&zone, Some(&name), record_type, content)
+            .await?;
+
+        // an empty name matches every record of the zone
+        Ok(records
+            .into_iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(domain))
+            .collect())
     }
 
-    pub fn delete_txt_record(&mut self, domain: &str) -> Result<(), InwxError> {
-        let id = self.get_record_id(domain)?;
+    /// Returns all records of a zone hosted by inwx.
+    pub async fn list_records(&mut self, zone: &str) -> Result<Vec<Record>, InwxError> {
+        self.query_records(zone, None, None, None).await
+    }
+
+    /// Returns the id of a TXT record of the domain, if `content` is set only a record with this value matches.
+    pub async fn get_record_id(
+        &mut self,
+        domain: &str,
+        content: Option<&str>,
+    ) -> Result<i32, InwxError> {
+        self.get_records(domain, Some("TXT"), content)
+            .await?
+            .first()
+            .map(|record| record.id)
+            .ok_or(InwxError::RecordNotFound)
+    }
 
+    /// Changes an existing record, the content has the format of the inwx api.
+    pub async fn update_record(
+        &mut self,
+        id: i32,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), InwxError> {
+        let mut parameters = vec![
+            RpcRequestParameter {
+                name: "id",
+                value: RpcRequestParameterValue::Int(id),
+            },
+            RpcRequestParameter {
+                name: "content",
+                value: RpcRequestParameterValue::String(content.to_owned()),
+            },
+        ];
+
+        if let Some(ttl) = ttl {
+            parameters.push(RpcRequestParameter {
+                name: "ttl",
+                value: RpcRequestParameterValue::Int(ttl),
+            });
+        }
+
+        if let Some(prio) = prio {
+            parameters.push(RpcRequestParameter {
+                name: "prio",
+                value: RpcRequestParameterValue::Int(prio),
+            });
+        }
+
+        let request = RpcRequest::new("nameserver.updateRecord", &parameters);
+
+        self.send_request(request).await?;
+
+        Ok(())
+    }
+
+    /// Deletes a record by its id.
+    pub async fn delete_record(&mut self, id: i32) -> Result<(), InwxError> {
         let request = RpcRequest::new(
             "nameserver.deleteRecord",
             &[RpcRequestParameter {
@@ -216,16 +508,153 @@ impl<'a> Inwx<'a> {
             }],
         );
 
-        self.send_request(request)?;
+        self.send_request(request).await?;
 
         Ok(())
     }
 
-    pub fn logout(mut self) -> Result<(), InwxError> {
+    /// Deletes the TXT record of the domain, if `content` is set only a record with this value is deleted.
+    pub async fn delete_txt_record(
+        &mut self,
+        domain: &str,
+        content: Option<&str>,
+    ) -> Result<(), InwxError> {
+        let id = self.get_record_id(domain, content).await?;
+
+        self.delete_record(id).await
+    }
+
+    /// The account the session belongs to.
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    /// Ends the session.
+    pub async fn logout(mut self) -> Result<(), InwxError> {
         let request = RpcRequest::new("account.logout", &[]);
 
-        self.send_request(request)?;
+        self.send_request(request).await?;
 
         Ok(())
     }
 }
+
+/// The blocking version of [`AsyncInwx`], it runs every call on a runtime of its own.
+///
+/// Tokio doesn't allow blocking within a runtime, so async code has to use [`AsyncInwx`]
+/// instead, [`Inwx::new`] fails within a runtime and the other methods would panic.
+pub struct Inwx {
+    runtime: Runtime,
+    inner: AsyncInwx,
+}
+
+impl Inwx {
+    /// Logs into the account like [`AsyncInwx::new`].
+    pub fn new(account: &Account) -> Result<Inwx, InwxError> {
+        if Handle::try_current().is_ok() {
+            return Err(InwxError::RuntimeError(
+                "Inwx can't be used within a tokio runtime, use AsyncInwx instead".to_owned(),
+            ));
+        }
+
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| InwxError::RuntimeError(e.to_string()))?;
+        let inner = runtime.block_on(AsyncInwx::new(account))?;
+
+        Ok(Inwx { runtime, inner })
+    }
+
+    /// Returns the names of all zones hosted by inwx for this account.
+    pub fn list_zones(&mut self) -> Result<Vec<String>, InwxError> {
+        self.runtime.block_on(self.inner.list_zones())
+    }
+
+    /// Splits the domain into the zone hosted by inwx and the name of the record within that zone.
+    pub fn split_domain(&mut self, domain: &str) -> Result<(String, String), InwxError> {
+        self.runtime.block_on(self.inner.split_domain(domain))
+    }
+
+    /// Creates a record, the content has the format of the inwx api (i.e. without the priority of MX records).
+    pub fn create_record(
+        &mut self,
+        domain: &str,
+        record_type: &str,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), InwxError> {
+        self.runtime.block_on(
+            self.inner
+                .create_record(domain, record_type, content, ttl, prio),
+        )
+    }
+
+    /// Creates a TXT record with the default ttl.
+    pub fn create_txt_record(&mut self, domain: &str, content: &str) -> Result<(), InwxError> {
+        self.runtime
+            .block_on(self.inner.create_txt_record(domain, content))
+    }
+
+    /// Returns the records of the domain, `record_type` and `content` narrow the result down.
+    pub fn get_records(
+        &mut self,
+        domain: &str,
+        record_type: Option<&str>,
+        content: Option<&str>,
+    ) -> Result<Vec<Record>, InwxError> {
+        self.runtime
+            .block_on(self.inner.get_records(domain, record_type, content))
+    }
+
+    /// Returns all records of a zone hosted by inwx.
+    pub fn list_records(&mut self, zone: &str) -> Result<Vec<Record>, InwxError> {
+        self.runtime.block_on(self.inner.list_records(zone))
+    }
+
+    /// Returns the id of a TXT record of the domain, if `content` is set only a record with this value matches.
+    pub fn get_record_id(&mut self, domain: &str, content: Option<&str>) -> Result<i32, InwxError> {
+        self.runtime
+            .block_on(self.inner.get_record_id(domain, content))
+    }
+
+    /// Changes an existing record, the content has the format of the inwx api.
+    pub fn update_record(
+        &mut self,
+        id: i32,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), InwxError> {
+        self.runtime
+            .block_on(self.inner.update_record(id, content, ttl, prio))
+    }
+
+    /// Deletes a record by its id.
+    pub fn delete_record(&mut self, id: i32) -> Result<(), InwxError> {
+        self.runtime.block_on(self.inner.delete_record(id))
+    }
+
+    /// Deletes the TXT record of the domain, if `content` is set only a record with this value is deleted.
+    pub fn delete_txt_record(
+        &mut self,
+        domain: &str,
+        content: Option<&str>,
+    ) -> Result<(), InwxError> {
+        self.runtime
+            .block_on(self.inner.delete_txt_record(domain, content))
+    }
+
+    /// The account the session belongs to.
+    pub fn account(&self) -> &Account {
+        self.inner.account()
+    }
+
+    /// Ends the session.
+    pub fn logout(self) -> Result<(), InwxError> {
+        let Inwx { runtime, inner } = self;
+
+        runtime.block_on(inner.logout())
+    }
+}
@@ -0,0 +1,83 @@
+//! A client for the dns api of [inwx](https://www.inwx.de) and the building blocks of the
+//! `letsencrypt-inwx` cli.
+//!
+//! The api consists of the items re-exported here and the [`config`], [`dns`], [`inwx`] and
+//! [`provider`] modules, the remaining modules of the cli are private.
+//!
+//! ```no_run
+//! use letsencrypt_inwx::{check_txt_record, read_config, Inwx};
+//!
+//! let config = read_config("/etc/letsencrypt-inwx.json").unwrap();
+//!
+//! let mut api = Inwx::new(&config.accounts[0]).unwrap();
+//! api.create_record("_acme-challenge.example.com", "TXT", "value", None, None)
+//!     .unwrap();
+//!
+//! for record in api.get_records("_acme-challenge.example.com", Some("TXT"), None).unwrap() {
+//!     println!("{} {}", record.id, record.content);
+//! }
+//!
+//! api.logout().unwrap();
+//!
+//! assert!(check_txt_record("8.8.8.8", "_acme-challenge.example.com", "value"));
+//! ```
+//!
+//! [`Inwx`] and the dns checks block the calling thread, [`AsyncInwx`],
+//! [`check_txt_record_async`] and [`lookup_real_domain_async`] are their counterparts for
+//! a tokio runtime:
+//!
+//! ```no_run
+//! # async fn example() {
+//! use letsencrypt_inwx::{read_config, AsyncInwx};
+//!
+//! let config = read_config("/etc/letsencrypt-inwx.json").unwrap();
+//!
+//! let mut api = AsyncInwx::new(&config.accounts[0]).await.unwrap();
+//! api.create_txt_record("_acme-challenge.example.com", "value")
+//!     .await
+//!     .unwrap();
+//! api.logout().await.unwrap();
+//! # }
+//! ```
+
+// the modules of the cli log their errors and only return `Err(())`
+#![allow(clippy::result_unit_err)]
+
+#[macro_use]
+extern crate log;
+
+pub mod config;
+pub mod dns;
+pub mod inwx;
+pub mod provider;
+
+mod acme;
+mod acme_dns;
+mod auth;
+mod certificate;
+mod challenge;
+mod cli;
+mod daemon;
+mod encryption;
+mod external_dns;
+mod http;
+mod httpreq;
+mod mock_server;
+mod powerdns;
+mod renew;
+mod rfc2136;
+mod rpc;
+#[cfg(test)]
+mod test_util;
+
+// the entry point of the letsencrypt-inwx binary
+#[doc(hidden)]
+pub use cli::run;
+pub use config::{read_config, Account, Config, ConfigError};
+pub use dns::{
+    check_txt_record, check_txt_record_async, lookup_real_domain, lookup_real_domain_async,
+};
+pub use encryption::EncryptionError;
+pub use inwx::{AsyncInwx, Inwx, InwxError, Record, MIN_TTL};
+pub use provider::{DnsProvider, FileProvider, ProviderError};
+pub use rpc::RpcError;
@@ -1,13 +1,4 @@
-#[macro_use]
-extern crate log;
-use env_logger;
 use env_logger::Env;
-mod cli;
-mod config;
-mod dns;
-mod inwx;
-mod rpc;
-
 use std::process::exit;
 
 fn main() {
@@ -15,7 +6,7 @@ fn main() {
     env_logger::init_from_env(env);
     openssl_probe::init_ssl_cert_env_vars();
 
-    if cli::run().is_err() {
+    if letsencrypt_inwx::run().is_err() {
         exit(1);
     }
 }
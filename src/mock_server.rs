@@ -0,0 +1,607 @@
+use crate::http::{self, header, MAX_BODY_SIZE};
+use crate::inwx::Record;
+use openssl::rand::rand_bytes;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use sxd_document::dom::{Document, Element};
+use sxd_document::writer::format_document;
+use sxd_document::{parser, Package};
+use sxd_xpath::evaluate_xpath;
+use tiny_http::{Header, Request, Response, Server};
+
+const SESSION_COOKIE: &str = "domrobot";
+
+// the result codes of the domrobot api
+const OK: i32 = 1000;
+const OK_LOGOUT: i32 = 1500;
+const PARAMETER_ERROR: i32 = 2003;
+const AUTHENTICATION_ERROR: i32 = 2200;
+const OBJECT_DOES_NOT_EXIST: i32 = 2303;
+const UNKNOWN_COMMAND: i32 = 2000;
+
+/// The initial state of the mock server.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Seed {
+    /// Any credentials are accepted if these are not set.
+    username: Option<String>,
+    password: Option<String>,
+    zones: BTreeMap<String, Vec<SeedRecord>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SeedRecord {
+    /// The name relative to the zone, empty for the zone itself.
+    #[serde(default)]
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    content: String,
+    #[serde(default = "default_ttl")]
+    ttl: i32,
+    #[serde(default)]
+    prio: i32,
+}
+
+fn default_ttl() -> i32 {
+    3600
+}
+
+/// An xml-rpc value of a response.
+enum Value {
+    Int(i32),
+    String(String),
+    Struct(Vec<(&'static str, Value)>),
+    Array(Vec<Value>),
+}
+
+fn append_value(doc: &Document, parent: Element, value: &Value) {
+    let element = doc.create_element("value");
+    parent.append_child(element);
+
+    match value {
+        Value::Int(int) => {
+            let int_element = doc.create_element("int");
+            int_element.append_child(doc.create_text(&int.to_string()));
+            element.append_child(int_element);
+        }
+        Value::String(string) => {
+            let string_element = doc.create_element("string");
+            string_element.append_child(doc.create_text(string));
+            element.append_child(string_element);
+        }
+        Value::Struct(members) => {
+            let struct_element = doc.create_element("struct");
+            element.append_child(struct_element);
+
+            for (name, value) in members {
+                let member = doc.create_element("member");
+                let name_element = doc.create_element("name");
+                name_element.append_child(doc.create_text(name));
+                member.append_child(name_element);
+                append_value(doc, member, value);
+                struct_element.append_child(member);
+            }
+        }
+        Value::Array(values) => {
+            let array = doc.create_element("array");
+            let data = doc.create_element("data");
+            array.append_child(data);
+            element.append_child(array);
+
+            for value in values {
+                append_value(doc, data, value);
+            }
+        }
+    }
+}
+
+fn method_response(code: i32, msg: &str, res_data: Option<Value>) -> Vec<u8> {
+    let mut members = vec![
+        ("code", Value::Int(code)),
+        ("msg", Value::String(msg.to_owned())),
+    ];
+
+    if let Some(res_data) = res_data {
+        members.push(("resData", res_data));
+    }
+
+    let package = Package::new();
+    let doc = package.as_document();
+
+    let method_response = doc.create_element("methodResponse");
+    doc.root().append_child(method_response);
+    let params = doc.create_element("params");
+    method_response.append_child(params);
+    let param = doc.create_element("param");
+    params.append_child(param);
+    append_value(&doc, param, &Value::Struct(members));
+
+    let mut body = Vec::new();
+    format_document(&doc, &mut body).unwrap();
+
+    body
+}
+
+/// Returns the method and the members of the parameter struct of a method call.
+fn parse_method_call(body: &str) -> Option<(String, HashMap<String, String>)> {
+    let package = parser::parse(body).ok()?;
+    let doc = package.as_document();
+
+    let method = evaluate_xpath(&doc, "/methodCall/methodName/text()")
+        .ok()?
+        .string();
+
+    let mut parameters = HashMap::new();
+    let count = match evaluate_xpath(&doc, "count(/methodCall/params/param/value/struct/member)") {
+        Ok(sxd_xpath::Value::Number(count)) => count as usize,
+        _ => 0,
+    };
+
+    for index in 1..=count {
+        let member = format!("/methodCall/params/param/value/struct/member[{}]", index);
+        let name = evaluate_xpath(&doc, &format!("{}/name/text()", member))
+            .ok()?
+            .string();
+        // untyped values are strings
+        let value = evaluate_xpath(&doc, &format!("{}/value//text()", member))
+            .ok()?
+            .string();
+
+        parameters.insert(name, value);
+    }
+
+    Some((method.trim().to_owned(), parameters))
+}
+
+fn record_value(record: &Record) -> Value {
+    Value::Struct(vec![
+        ("id", Value::Int(record.id)),
+        ("name", Value::String(record.name.clone())),
+        ("type", Value::String(record.record_type.clone())),
+        ("content", Value::String(record.content.clone())),
+        ("ttl", Value::Int(record.ttl)),
+        ("prio", Value::Int(record.prio)),
+    ])
+}
+
+fn session_id(request: &Request) -> Option<String> {
+    header(request, "Cookie")?
+        .split(';')
+        .filter_map(|cookie| cookie.trim().strip_prefix(SESSION_COOKIE))
+        .filter_map(|cookie| cookie.strip_prefix('='))
+        .map(|id| id.to_owned())
+        .next()
+}
+
+fn absolute_name(zone: &str, name: &str) -> String {
+    match name {
+        "" => zone.to_owned(),
+        name => format!("{}.{}", name.to_lowercase(), zone),
+    }
+}
+
+struct MockServer {
+    username: Option<String>,
+    password: Option<String>,
+    zones: BTreeMap<String, Vec<Record>>,
+    sessions: HashSet<String>,
+    next_id: i32,
+}
+
+type MethodResult = Result<(i32, Option<Value>), (i32, &'static str)>;
+
+impl MockServer {
+    fn new(seed: Seed) -> MockServer {
+        let mut server = MockServer {
+            username: seed.username,
+            password: seed.password,
+            zones: BTreeMap::new(),
+            sessions: HashSet::new(),
+            next_id: 1,
+        };
+
+        for (zone, records) in seed.zones {
+            let zone = zone.trim_end_matches('.').to_lowercase();
+            let records = records
+                .into_iter()
+                .map(|record| Record {
+                    id: server.next_id(),
+                    name: absolute_name(&zone, &record.name),
+                    record_type: record.record_type.to_uppercase(),
+                    content: record.content,
+                    ttl: record.ttl,
+                    prio: record.prio,
+                })
+                .collect();
+
+            server.zones.insert(zone, records);
+        }
+
+        server
+    }
+
+    fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn find_record(&mut self, id: &str) -> Option<&mut Record> {
+        let id: i32 = id.parse().ok()?;
+
+        self.zones
+            .values_mut()
+            .flat_map(|records| records.iter_mut())
+            .find(|record| record.id == id)
+    }
+
+    fn login(
+        &mut self,
+        parameters: &HashMap<String, String>,
+    ) -> Result<String, (i32, &'static str)> {
+        let matches = |expected: &Option<String>, name| {
+            expected
+                .as_ref()
+                .is_none_or(|expected| parameters.get(name) == Some(expected))
+        };
+
+        if !matches(&self.username, "user") || !matches(&self.password, "pass") {
+            return Err((AUTHENTICATION_ERROR, "Authentication error"));
+        }
+
+        let mut id = [0; 16];
+        rand_bytes(&mut id).unwrap();
+        let id: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        self.sessions.insert(id.clone());
+
+        Ok(id)
+    }
+
+    fn call(&mut self, method: &str, parameters: &HashMap<String, String>) -> MethodResult {
+        let parameter = |name: &str| parameters.get(name).map(|value| value.as_str());
+        let required = |name: &str| parameter(name).ok_or((PARAMETER_ERROR, "Parameter error"));
+        let int = |name: &str, default: i32| {
+            parameter(name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| (PARAMETER_ERROR, "Parameter value syntax error"))
+                })
+                .unwrap_or(Ok(default))
+        };
+
+        match method {
+            "account.logout" => Ok((OK_LOGOUT, None)),
+            "nameserver.list" => {
+                let page_size = int("pagelimit", 20)?.max(1) as usize;
+                let page = int("page", 1)?.max(1) as usize;
+
+                let domains = self
+                    .zones
+                    .keys()
+                    .skip((page - 1) * page_size)
+                    .take(page_size)
+                    .map(|zone| Value::Struct(vec![("domain", Value::String(zone.clone()))]))
+                    .collect();
+
+                Ok((
+                    OK,
+                    Some(Value::Struct(vec![
+                        ("count", Value::Int(self.zones.len() as i32)),
+                        ("domains", Value::Array(domains)),
+                    ])),
+                ))
+            }
+            "nameserver.info" => {
+                let zone = required("domain")?.trim_end_matches('.').to_lowercase();
+                let records = self
+                    .zones
+                    .get(&zone)
+                    .ok_or((OBJECT_DOES_NOT_EXIST, "Object does not exist"))?;
+
+                let name = parameter("name")
+                    .filter(|name| !name.is_empty())
+                    .map(|name| absolute_name(&zone, name));
+
+                let records = records
+                    .iter()
+                    .filter(|record| name.as_ref().is_none_or(|name| record.name == *name))
+                    .filter(|record| {
+                        parameter("type").is_none_or(|record_type| {
+                            record.record_type.eq_ignore_ascii_case(record_type)
+                        })
+                    })
+                    .filter(|record| {
+                        parameter("content").is_none_or(|content| record.content == content)
+                    })
+                    .map(record_value)
+                    .collect();
+
+                Ok((
+                    OK,
+                    Some(Value::Struct(vec![
+                        ("domain", Value::String(zone)),
+                        ("record", Value::Array(records)),
+                    ])),
+                ))
+            }
+            "nameserver.createRecord" => {
+                let zone = required("domain")?.trim_end_matches('.').to_lowercase();
+
+                if !self.zones.contains_key(&zone) {
+                    return Err((OBJECT_DOES_NOT_EXIST, "Object does not exist"));
+                }
+
+                let record = Record {
+                    id: self.next_id(),
+                    name: absolute_name(&zone, parameter("name").unwrap_or("")),
+                    record_type: required("type")?.to_uppercase(),
+                    content: required("content")?.to_owned(),
+                    ttl: int("ttl", default_ttl())?,
+                    prio: int("prio", 0)?,
+                };
+                let id = record.id;
+
+                self.zones.get_mut(&zone).unwrap().push(record);
+
+                Ok((OK, Some(Value::Struct(vec![("id", Value::Int(id))]))))
+            }
+            "nameserver.updateRecord" => {
+                let content = required("content")?.to_owned();
+                let ttl = parameter("ttl").map(|_| int("ttl", 0)).transpose()?;
+                let prio = parameter("prio").map(|_| int("prio", 0)).transpose()?;
+                let record = self
+                    .find_record(required("id")?)
+                    .ok_or((OBJECT_DOES_NOT_EXIST, "Object does not exist"))?;
+
+                record.content = content;
+                record.ttl = ttl.unwrap_or(record.ttl);
+                record.prio = prio.unwrap_or(record.prio);
+
+                Ok((OK, None))
+            }
+            "nameserver.deleteRecord" => {
+                let id: i32 = required("id")?
+                    .parse()
+                    .map_err(|_| (PARAMETER_ERROR, "Parameter value syntax error"))?;
+
+                for records in self.zones.values_mut() {
+                    if let Some(position) = records.iter().position(|record| record.id == id) {
+                        records.remove(position);
+                        return Ok((OK, None));
+                    }
+                }
+
+                Err((OBJECT_DOES_NOT_EXIST, "Object does not exist"))
+            }
+            _ => Err((UNKNOWN_COMMAND, "Unknown command")),
+        }
+    }
+
+    fn handle(&mut self, mut request: Request) {
+        let mut body = String::new();
+
+        if request
+            .as_reader()
+            .take(MAX_BODY_SIZE)
+            .read_to_string(&mut body)
+            .is_err()
+        {
+            return http::respond_empty(request, 400);
+        }
+
+        let (method, parameters) = match parse_method_call(&body) {
+            Some(call) => call,
+            None => return http::respond_empty(request, 400),
+        };
+
+        let mut cookie = None;
+
+        let result = match method.as_str() {
+            "account.login" => self.login(&parameters).map(|id| {
+                cookie = Some(id);
+                (OK, None)
+            }),
+            _ => match session_id(&request) {
+                Some(ref id) if self.sessions.contains(id) => {
+                    if method == "account.logout" {
+                        self.sessions.remove(id);
+                    }
+
+                    self.call(&method, &parameters)
+                }
+                _ => Err((AUTHENTICATION_ERROR, "Authentication error")),
+            },
+        };
+
+        let body = match result {
+            Ok((code, res_data)) => {
+                info!("{} => {}", method, code);
+                method_response(code, "Command completed successfully", res_data)
+            }
+            Err((code, msg)) => {
+                warn!("{} => {} {}", method, code, msg);
+                method_response(code, msg, None)
+            }
+        };
+
+        let mut response = Response::from_data(body)
+            .with_header(Header::from_bytes("Content-Type", "text/xml; charset=UTF-8").unwrap());
+
+        if let Some(id) = cookie {
+            let value = format!("{}={}; Path=/", SESSION_COOKIE, id);
+            response.add_header(Header::from_bytes("Set-Cookie", value).unwrap());
+        }
+
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to send response: {}", e);
+        }
+    }
+}
+
+/// Serves the parts of the inwx domrobot xml-rpc api used by this crate with in-memory zones.
+pub fn serve(listen: &str, seed_file: &str) -> Result<(), ()> {
+    let file = File::open(seed_file).map_err(|e| error!("Failed to open seed file: {}", e))?;
+    let seed: Seed = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| error!("Failed to parse seed file: {}", e))?;
+
+    let server = http::bind(listen)?;
+
+    handle_requests(seed, &server);
+
+    Ok(())
+}
+
+fn handle_requests(seed: Seed, server: &Server) {
+    let mut mock = MockServer::new(seed);
+
+    for request in server.incoming_requests() {
+        mock.handle(request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Account, Secret};
+    use crate::inwx::{AsyncInwx, Inwx, InwxError};
+    use crate::test_util::spawn_server;
+    use serde_json::json;
+
+    /// Starts the server with the zones example.com and sub.example.com and returns an account
+    /// using it.
+    fn start() -> Account {
+        let seed: Seed = serde_json::from_value(json!({
+            "username": "test",
+            "password": "secret",
+            "zones": {
+                "example.com": [{ "type": "A", "content": "192.0.2.1" }],
+                "sub.example.com": [],
+            },
+        }))
+        .unwrap();
+
+        let url = spawn_server(move |server| handle_requests(seed, &server));
+
+        let mut account = Account::new("test", "secret");
+        account
+            .set_api_url(Some(&format!("{}/xmlrpc/", url)))
+            .set_no_proxy(Some("*"));
+        account
+    }
+
+    #[test]
+    fn create_and_delete() {
+        let mut api = Inwx::new(&start()).unwrap();
+
+        assert_eq!(
+            api.list_zones().unwrap(),
+            vec!["example.com".to_owned(), "sub.example.com".to_owned()]
+        );
+
+        api.create_txt_record("_acme-challenge.example.com", "value")
+            .unwrap();
+
+        let records = api
+            .get_records("_acme-challenge.example.com", Some("TXT"), None)
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].content, "value");
+
+        api.delete_txt_record("_acme-challenge.example.com", Some("value"))
+            .unwrap();
+        assert!(api
+            .get_records("_acme-challenge.example.com", Some("TXT"), None)
+            .unwrap()
+            .is_empty());
+
+        assert!(matches!(
+            api.create_txt_record("_acme-challenge.example.org", "value"),
+            Err(InwxError::DomainNotFound)
+        ));
+
+        api.logout().unwrap();
+    }
+
+    #[test]
+    fn wrong_password() {
+        let mut account = start();
+        account.password = Secret::new("wrong".to_owned());
+
+        match Inwx::new(&account) {
+            Err(InwxError::RpcError(e)) => assert_eq!(e.code(), Some(AUTHENTICATION_ERROR)),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn within_runtime() {
+        let account = start();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        match runtime.block_on(async { Inwx::new(&account) }) {
+            Err(InwxError::RuntimeError(_)) => (),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn split_domain() {
+        let mut api = Inwx::new(&start()).unwrap();
+
+        assert_eq!(
+            api.split_domain("example.com").unwrap(),
+            ("example.com".to_owned(), "".to_owned())
+        );
+        assert_eq!(
+            api.split_domain("www.example.com").unwrap(),
+            ("example.com".to_owned(), "www".to_owned())
+        );
+        // the nested zone wins, even though example.com is listed first
+        assert_eq!(
+            api.split_domain("_acme-challenge.www.sub.example.com")
+                .unwrap(),
+            (
+                "sub.example.com".to_owned(),
+                "_acme-challenge.www".to_owned()
+            )
+        );
+        assert!(matches!(
+            api.split_domain("notexample.com"),
+            Err(InwxError::DomainNotFound)
+        ));
+    }
+
+    #[test]
+    fn async_api() {
+        let account = start();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // spawning only compiles if the futures of the api are Send
+        let task = runtime.spawn(async move {
+            let mut api = AsyncInwx::new(&account).await.unwrap();
+            api.create_txt_record("_acme-challenge.sub.example.com", "value")
+                .await
+                .unwrap();
+            let records = api
+                .get_records("_acme-challenge.sub.example.com", Some("TXT"), None)
+                .await
+                .unwrap();
+            api.logout().await.unwrap();
+
+            records
+        });
+
+        let records = runtime.block_on(task).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].content, "value");
+    }
+}
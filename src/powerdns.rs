@@ -0,0 +1,598 @@
+use crate::auth::{authorize, find_token_by_secret, may_read, may_read_zone, Client};
+use crate::challenge::{execute_api_commands, list_zones, Sessions};
+use crate::config::Config;
+use crate::http::{bind, header, path, read_json, respond_empty, respond_json};
+use crate::inwx::{Record, MIN_TTL};
+use crate::provider::{DnsProvider, ProviderError};
+use openssl::memcmp;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::TryFrom;
+use tiny_http::{Method, Request};
+
+const SERVER_URL: &str = "/api/v1/servers/localhost";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PdnsRecord {
+    content: String,
+    #[serde(default)]
+    disabled: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct RrSet {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    ttl: i32,
+    records: Vec<PdnsRecord>,
+    comments: Vec<()>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+enum ChangeType {
+    Replace,
+    Delete,
+}
+
+#[derive(Deserialize, Debug)]
+struct RrSetChange {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    #[serde(default)]
+    ttl: Option<i32>,
+    changetype: ChangeType,
+    #[serde(default)]
+    records: Vec<PdnsRecord>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PatchZone {
+    rrsets: Vec<RrSetChange>,
+}
+
+/// A parsed rrset change with the contents in the format of the inwx api.
+struct Change {
+    name: String,
+    record_type: String,
+    ttl: Option<i32>,
+    changetype: ChangeType,
+    contents: Vec<(String, Option<i32>)>,
+}
+
+fn quote_txt(content: &str) -> String {
+    format!("\"{}\"", content.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Concatenates the character strings of a TXT record in zone file format, returns `None` if
+/// the content is malformed.
+fn unquote_txt(content: &str) -> Option<String> {
+    let content = content.trim();
+
+    if !content.starts_with('"') {
+        return Some(content.to_owned());
+    }
+
+    let mut bytes = content.bytes();
+    let mut result = Vec::new();
+    let mut quoted = false;
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'"' => quoted = !quoted,
+            b'\\' if quoted => match bytes.next()? {
+                // a \DDD escape is always three decimal digits of a byte value
+                digit if digit.is_ascii_digit() => {
+                    let mut value = u32::from(digit - b'0');
+
+                    for _ in 0..2 {
+                        let digit = bytes.next().filter(u8::is_ascii_digit)?;
+                        value = value * 10 + u32::from(digit - b'0');
+                    }
+
+                    result.push(u8::try_from(value).ok()?);
+                }
+                escaped => result.push(escaped),
+            },
+            byte if quoted => result.push(byte),
+            // whitespace between the character strings
+            byte if byte.is_ascii_whitespace() => {}
+            _ => return None,
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&result).into_owned())
+}
+
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+/// Converts the content of an inwx record into the zone file format used by PowerDNS.
+fn record_to_content(record: &Record) -> String {
+    match record.record_type.as_str() {
+        "MX" => format!("{} {}", record.prio, fqdn(&record.content)),
+        "SRV" => {
+            let mut parts: Vec<String> = record
+                .content
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+
+            if let Some(target) = parts.last_mut() {
+                *target = fqdn(target);
+            }
+
+            format!("{} {}", record.prio, parts.join(" "))
+        }
+        "CNAME" | "NS" | "PTR" => fqdn(&record.content),
+        "SOA" => {
+            let mut parts: Vec<String> = record
+                .content
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+
+            for part in parts.iter_mut().take(2) {
+                *part = fqdn(part);
+            }
+
+            parts.join(" ")
+        }
+        "TXT" | "SPF" => quote_txt(&record.content),
+        _ => record.content.to_owned(),
+    }
+}
+
+/// Converts content in zone file format into the content and priority used by inwx.
+fn content_to_record(record_type: &str, content: &str) -> Option<(String, Option<i32>)> {
+    match record_type {
+        "MX" | "SRV" => {
+            let mut parts = content.trim().splitn(2, ' ');
+            let prio = parts.next()?.parse().ok()?;
+            let content = parts.next()?.trim();
+
+            Some((content.trim_end_matches('.').to_owned(), Some(prio)))
+        }
+        "CNAME" | "NS" | "PTR" => Some((content.trim().trim_end_matches('.').to_owned(), None)),
+        "TXT" | "SPF" => Some((unquote_txt(content)?, None)),
+        _ => Some((content.trim().to_owned(), None)),
+    }
+}
+
+fn in_zone(name: &str, zone: &str) -> bool {
+    name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+fn zone_object(zone: &str, serial: u64) -> serde_json::Value {
+    json!({
+        "id": fqdn(zone),
+        "name": fqdn(zone),
+        "type": "Zone",
+        "url": format!("{}/zones/{}", SERVER_URL, fqdn(zone)),
+        "kind": "Native",
+        "serial": serial,
+        "notified_serial": serial,
+        "edited_serial": serial,
+        "masters": [],
+        "dnssec": false,
+        "nsec3param": "",
+        "nsec3narrow": false,
+        "presigned": false,
+        "soa_edit": "",
+        "soa_edit_api": "",
+        "api_rectify": false,
+        "account": "",
+    })
+}
+
+fn server_object() -> serde_json::Value {
+    json!({
+        "id": "localhost",
+        "type": "Server",
+        "daemon_type": "authoritative",
+        "version": concat!("letsencrypt-inwx ", env!("CARGO_PKG_VERSION")),
+        "url": SERVER_URL,
+        "config_url": format!("{}/config{{/config_setting}}", SERVER_URL),
+        "zones_url": format!("{}/zones{{/zone}}", SERVER_URL),
+    })
+}
+
+fn respond_error(request: Request, status: u16, error: &str) {
+    respond_json(request, status, &json!({ "error": error }));
+}
+
+/// Replaces the records of an rrset, records with unchanged content are kept and
+/// surplus records are reused for new content.
+fn replace_records(
+    api: &mut dyn DnsProvider,
+    change: &Change,
+    mut existing: Vec<&Record>,
+) -> Result<(), ProviderError> {
+    let mut missing = Vec::new();
+
+    for (content, prio) in &change.contents {
+        let position = existing.iter().position(|record| {
+            &record.content == content && prio.is_none_or(|prio| prio == record.prio)
+        });
+
+        match position {
+            Some(position) => {
+                let record = existing.remove(position);
+
+                if change.ttl.is_some_and(|ttl| ttl != record.ttl) {
+                    api.update_record(record.id, &record.content, change.ttl, *prio)?;
+                }
+            }
+            None => missing.push((content, prio)),
+        }
+    }
+
+    let mut existing = existing.into_iter();
+
+    for (content, prio) in missing {
+        match existing.next() {
+            Some(record) => api.update_record(record.id, content, change.ttl, *prio)?,
+            None => api.create_record(
+                &change.name,
+                &change.record_type,
+                content,
+                change.ttl,
+                *prio,
+            )?,
+        }
+    }
+
+    for record in existing {
+        api.delete_record(record.id)?;
+    }
+
+    Ok(())
+}
+
+struct PowerDnsServer<'a> {
+    config: &'a Config,
+    sessions: Sessions,
+}
+
+impl<'a> PowerDnsServer<'a> {
+    fn client(&self, request: &Request) -> Option<Client<'a>> {
+        let key = header(request, "X-API-Key")?;
+
+        if let Some(token) = find_token_by_secret(self.config, key) {
+            return Some(Client::Token(token));
+        }
+
+        match self.config.powerdns.api_key {
+            Some(ref api_key)
+                if api_key.expose().len() == key.len()
+                    && memcmp::eq(api_key.expose().as_bytes(), key.as_bytes()) =>
+            {
+                Some(Client::Unrestricted("powerdns"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Runs `op` with the account hosting the zone, returns `None` if no account hosts it.
+    fn with_zone<F>(&mut self, zone: &str, mut op: F) -> Option<Result<(), ()>>
+    where
+        F: FnMut(&mut dyn DnsProvider) -> Result<(), ProviderError>,
+    {
+        let mut missing = false;
+
+        let result = execute_api_commands(self.config, &mut self.sessions, zone, |api| {
+            // only the last account decides, an error of another account is not a missing zone
+            missing = false;
+
+            match api.find_zone(zone) {
+                // the zone has to be hosted by the account itself, not only a parent zone
+                Ok((_, name)) if name.is_empty() => op(api),
+                Ok(_) | Err(ProviderError::DomainNotFound) => {
+                    missing = true;
+                    Err(ProviderError::DomainNotFound)
+                }
+                Err(e) => Err(e),
+            }
+        });
+
+        match (result, missing) {
+            (Ok(_), _) => Some(Ok(())),
+            (Err(_), false) => Some(Err(())),
+            (Err(_), true) => None,
+        }
+    }
+
+    fn list_zones(&mut self, request: Request, client: &Client) {
+        match list_zones(self.config, &mut self.sessions) {
+            Ok(zones) => {
+                let zones: Vec<_> = zones
+                    .iter()
+                    .filter(|zone| may_read_zone(client, zone))
+                    .map(|zone| zone_object(zone, 0))
+                    .collect();
+                respond_json(request, 200, &zones);
+            }
+            Err(_) => respond_error(request, 500, "Listing the zones failed"),
+        }
+    }
+
+    fn get_zone(&mut self, request: Request, zone: &str, client: &Client) {
+        // the zones outside of the scope of the token are not revealed
+        if !may_read_zone(client, zone) {
+            return respond_error(request, 404, "Could not find domain");
+        }
+
+        let mut records = Vec::new();
+
+        match self.with_zone(zone, |api| {
+            records = api.list_records(zone)?;
+            Ok(())
+        }) {
+            Some(Ok(_)) => {}
+            Some(Err(_)) => return respond_error(request, 500, "Reading the zone failed"),
+            None => return respond_error(request, 404, "Could not find domain"),
+        }
+
+        let mut rrsets: Vec<RrSet> = Vec::new();
+        let mut serial = 0;
+
+        for record in &records {
+            if record.record_type == "SOA" {
+                serial = record
+                    .content
+                    .split_whitespace()
+                    .nth(2)
+                    .and_then(|serial| serial.parse().ok())
+                    .unwrap_or(0);
+            }
+
+            if !may_read(client, &record.name, &record.record_type) {
+                continue;
+            }
+
+            let name = fqdn(&record.name);
+            let content = PdnsRecord {
+                content: record_to_content(record),
+                disabled: false,
+            };
+
+            match rrsets
+                .iter_mut()
+                .find(|rrset| rrset.name == name && rrset.record_type == record.record_type)
+            {
+                Some(rrset) => rrset.records.push(content),
+                None => rrsets.push(RrSet {
+                    name,
+                    record_type: record.record_type.to_owned(),
+                    ttl: record.ttl,
+                    records: vec![content],
+                    comments: vec![],
+                }),
+            }
+        }
+
+        let mut body = zone_object(zone, serial);
+        body["rrsets"] = json!(rrsets);
+
+        respond_json(request, 200, &body);
+    }
+
+    fn parse_change(zone: &str, rrset: RrSetChange) -> Result<Change, String> {
+        let name = rrset.name.trim_end_matches('.').to_lowercase();
+        let record_type = rrset.record_type.to_uppercase();
+
+        if !in_zone(&name, zone) {
+            return Err(format!("RRset {} is not in zone {}", rrset.name, zone));
+        }
+
+        if record_type == "SOA" {
+            return Err("The SOA record is managed by inwx".to_owned());
+        }
+
+        let mut contents = Vec::new();
+
+        for record in rrset.records.iter().filter(|record| !record.disabled) {
+            contents.push(
+                content_to_record(&record_type, &record.content).ok_or_else(|| {
+                    format!("Invalid {} record content {}", record_type, record.content)
+                })?,
+            );
+        }
+
+        Ok(Change {
+            name,
+            record_type,
+            ttl: rrset.ttl.map(|ttl| ttl.max(MIN_TTL)),
+            changetype: rrset.changetype,
+            contents,
+        })
+    }
+
+    fn patch_zone(&mut self, mut request: Request, zone: &str, client: &Client) {
+        let body: PatchZone = match read_json(&mut request) {
+            Some(body) => body,
+            None => return respond_error(request, 400, "Invalid JSON"),
+        };
+
+        let mut changes = Vec::new();
+
+        for rrset in body.rrsets {
+            match Self::parse_change(zone, rrset) {
+                Ok(change) => changes.push(change),
+                Err(e) => return respond_error(request, 422, &e),
+            }
+        }
+
+        // reject the whole patch before anything has been changed
+        for change in &changes {
+            let action = match change.changetype {
+                ChangeType::Replace => "replace",
+                ChangeType::Delete => "delete",
+            };
+
+            if !authorize(
+                self.config,
+                client,
+                action,
+                &change.name,
+                &change.record_type,
+            ) {
+                return respond_error(request, 403, "Forbidden");
+            }
+        }
+
+        let result = self.with_zone(zone, |api| {
+            let records = api.list_records(zone)?;
+
+            for change in &changes {
+                let existing: Vec<&Record> = records
+                    .iter()
+                    .filter(|record| {
+                        record.name.eq_ignore_ascii_case(&change.name)
+                            && record.record_type == change.record_type
+                    })
+                    .collect();
+
+                info!(
+                    "Changing the {} records of {}",
+                    change.record_type, change.name
+                );
+
+                match change.changetype {
+                    ChangeType::Replace => replace_records(api, change, existing)?,
+                    ChangeType::Delete => {
+                        for record in existing {
+                            api.delete_record(record.id)?;
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        });
+
+        match result {
+            Some(Ok(_)) => respond_empty(request, 204),
+            Some(Err(_)) => respond_error(request, 500, "Changing the zone failed"),
+            None => respond_error(request, 404, "Could not find domain"),
+        }
+    }
+
+    fn handle(&mut self, request: Request) {
+        let client = match self.client(&request) {
+            Some(client) => client,
+            None => return respond_error(request, 401, "Unauthorized"),
+        };
+
+        let path = path(&request).trim_end_matches('/').to_owned();
+
+        if path == "/api" {
+            return respond_json(request, 200, &json!([{ "url": "/api/v1", "version": 1 }]));
+        } else if path == "/api/v1/servers" {
+            return respond_json(request, 200, &json!([server_object()]));
+        } else if path == SERVER_URL {
+            return respond_json(request, 200, &server_object());
+        }
+
+        let zone_path = match path.strip_prefix(&format!("{}/zones", SERVER_URL)) {
+            Some(zone_path) => zone_path,
+            None => return respond_error(request, 404, "Not Found"),
+        };
+
+        if zone_path.is_empty() {
+            return match request.method() {
+                Method::Get => self.list_zones(request, &client),
+                _ => respond_error(request, 405, "Method Not Allowed"),
+            };
+        }
+
+        let mut parts = zone_path.trim_start_matches('/').splitn(2, '/');
+        let zone = parts
+            .next()
+            .unwrap_or("")
+            .trim_end_matches('.')
+            .to_lowercase();
+
+        match (request.method(), parts.next()) {
+            (Method::Get, None) => self.get_zone(request, &zone, &client),
+            (Method::Patch, None) => self.patch_zone(request, &zone, &client),
+            // inwx notifies the secondary nameservers itself
+            (Method::Put, Some("notify")) => {
+                respond_json(request, 200, &json!({ "result": "Notification queued" }))
+            }
+            _ => respond_error(request, 404, "Not Found"),
+        }
+    }
+}
+
+/// Serves the parts of the PowerDNS http api needed by dns tooling on top of the inwx api.
+pub fn serve(config: &Config) -> Result<(), ()> {
+    if config.powerdns.api_key.is_none() && config.tokens.is_empty() {
+        error!("Neither powerdns.api_key nor tokens are configured");
+        return Err(());
+    }
+
+    let mut server = PowerDnsServer {
+        config,
+        sessions: Sessions::new(),
+    };
+
+    let listener = bind(&config.powerdns.listen)?;
+
+    for request in listener.incoming_requests() {
+        debug!("{} {}", request.method(), request.url());
+
+        server.handle(request);
+    }
+
+    server.sessions.logout();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_round_trip() {
+        for content in &["value", "with \"quotes\"", "back\\slash\\", "ünïcode", ""] {
+            assert_eq!(unquote_txt(&quote_txt(content)).as_deref(), Some(*content));
+        }
+    }
+
+    #[test]
+    fn unquote() {
+        assert_eq!(unquote_txt("unquoted").as_deref(), Some("unquoted"));
+        assert_eq!(
+            unquote_txt(" \"v=spf1 \" \"-all\"\t\"\" ").as_deref(),
+            Some("v=spf1 -all")
+        );
+        assert_eq!(unquote_txt("\"a\\065\\\"b\"").as_deref(), Some("aA\"b"));
+        assert_eq!(unquote_txt("\"\\255\"").map(|value| value.len()), Some(3));
+    }
+
+    #[test]
+    fn malformed_txt() {
+        for content in &[
+            "\"\\1\"",
+            "\"\\12\"",
+            "\"\\1x2\"",
+            "\"\\256\"",
+            "\"unterminated",
+            "\"escape\\",
+            "\"a\"b\"c\"",
+        ] {
+            assert_eq!(unquote_txt(content), None, "{}", content);
+        }
+
+        assert_eq!(content_to_record("TXT", "\"\\1\""), None);
+    }
+}
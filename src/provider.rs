@@ -0,0 +1,449 @@
+use crate::config::RecordsFile;
+use crate::inwx::{Inwx, InwxError, Record};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+// the ttl inwx assigns to records created without one
+const DEFAULT_TTL: i32 = 3600;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ProviderError {
+    /// None of the zones of the provider contains the domain.
+    DomainNotFound,
+    RecordNotFound,
+    InwxError(InwxError),
+    IoError(io::Error),
+    ParseError(serde_json::Error),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderError::DomainNotFound => {
+                write!(f, "There is no nameserver for the specified domain")
+            }
+            ProviderError::RecordNotFound => write!(f, "The specified record does not exist"),
+            ProviderError::InwxError(e) => write!(f, "{}", e),
+            ProviderError::IoError(e) => write!(f, "Failed to access the records file: {}", e),
+            ProviderError::ParseError(e) => write!(f, "Failed to parse the records file: {}", e),
+        }
+    }
+}
+
+impl From<InwxError> for ProviderError {
+    fn from(error: InwxError) -> ProviderError {
+        match error {
+            InwxError::DomainNotFound => ProviderError::DomainNotFound,
+            InwxError::RecordNotFound => ProviderError::RecordNotFound,
+            error => ProviderError::InwxError(error),
+        }
+    }
+}
+
+impl From<io::Error> for ProviderError {
+    fn from(error: io::Error) -> ProviderError {
+        ProviderError::IoError(error)
+    }
+}
+
+impl From<serde_json::Error> for ProviderError {
+    fn from(error: serde_json::Error) -> ProviderError {
+        ProviderError::ParseError(error)
+    }
+}
+
+/// The record operations the challenge flow and the server modes are built on.
+///
+/// Names are fully qualified without a trailing dot and contents have the format of the inwx
+/// api, i.e. the priority of MX and SRV records is passed separately.
+pub trait DnsProvider {
+    /// Returns the names of all zones of the provider.
+    fn list_zones(&mut self) -> Result<Vec<String>, ProviderError>;
+
+    /// Splits the domain into the zone containing it and the name of the record within that zone.
+    fn find_zone(&mut self, domain: &str) -> Result<(String, String), ProviderError>;
+
+    fn create_record(
+        &mut self,
+        domain: &str,
+        record_type: &str,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), ProviderError>;
+
+    /// Returns the records of the domain, optionally only those with the type and content.
+    fn get_records(
+        &mut self,
+        domain: &str,
+        record_type: Option<&str>,
+        content: Option<&str>,
+    ) -> Result<Vec<Record>, ProviderError>;
+
+    /// Returns all records of a zone.
+    fn list_records(&mut self, zone: &str) -> Result<Vec<Record>, ProviderError>;
+
+    /// Changes the content of a record, the ttl and priority are kept if they are not set.
+    fn update_record(
+        &mut self,
+        id: i32,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), ProviderError>;
+
+    fn delete_record(&mut self, id: i32) -> Result<(), ProviderError>;
+
+    /// Creates a TXT record with the default ttl.
+    fn create_txt_record(&mut self, domain: &str, content: &str) -> Result<(), ProviderError> {
+        self.create_record(domain, "TXT", content, None, None)
+    }
+
+    /// Deletes a TXT record of the domain, if `content` is set only a record with this value.
+    fn delete_txt_record(
+        &mut self,
+        domain: &str,
+        content: Option<&str>,
+    ) -> Result<(), ProviderError> {
+        let id = self
+            .get_records(domain, Some("TXT"), content)?
+            .first()
+            .map(|record| record.id)
+            .ok_or(ProviderError::RecordNotFound)?;
+
+        self.delete_record(id)
+    }
+}
+
+impl DnsProvider for Inwx {
+    fn list_zones(&mut self) -> Result<Vec<String>, ProviderError> {
+        Ok(Inwx::list_zones(self)?)
+    }
+
+    fn find_zone(&mut self, domain: &str) -> Result<(String, String), ProviderError> {
+        Ok(self.split_domain(domain)?)
+    }
+
+    fn create_record(
+        &mut self,
+        domain: &str,
+        record_type: &str,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), ProviderError> {
+        Ok(Inwx::create_record(
+            self,
+            domain,
+            record_type,
+            content,
+            ttl,
+            prio,
+        )?)
+    }
+
+    fn get_records(
+        &mut self,
+        domain: &str,
+        record_type: Option<&str>,
+        content: Option<&str>,
+    ) -> Result<Vec<Record>, ProviderError> {
+        Ok(Inwx::get_records(self, domain, record_type, content)?)
+    }
+
+    fn list_records(&mut self, zone: &str) -> Result<Vec<Record>, ProviderError> {
+        Ok(Inwx::list_records(self, zone)?)
+    }
+
+    fn update_record(
+        &mut self,
+        id: i32,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), ProviderError> {
+        Ok(Inwx::update_record(self, id, content, ttl, prio)?)
+    }
+
+    fn delete_record(&mut self, id: i32) -> Result<(), ProviderError> {
+        Ok(Inwx::delete_record(self, id)?)
+    }
+}
+
+/// Keeps the records of the configured zones in a local json file.
+///
+/// The file is read and written on every call, so other processes (i.e. tests) can inspect or
+/// prepare the records in between.
+pub struct FileProvider {
+    path: PathBuf,
+    zones: Vec<String>,
+}
+
+impl FileProvider {
+    pub fn new(records_file: &RecordsFile) -> FileProvider {
+        FileProvider {
+            path: PathBuf::from(&records_file.path),
+            zones: records_file
+                .zones
+                .iter()
+                .map(|zone| zone.trim_end_matches('.').to_lowercase())
+                .collect(),
+        }
+    }
+
+    fn read(&self) -> Result<Vec<Record>, ProviderError> {
+        match File::open(&self.path) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, records: &[Record]) -> Result<(), ProviderError> {
+        // replace the file at once, so a reader never sees a partially written file
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(records)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    fn in_zone(record: &Record, zone: &str) -> bool {
+        let name = record.name.to_lowercase();
+
+        name == zone || name.ends_with(&format!(".{}", zone))
+    }
+}
+
+impl DnsProvider for FileProvider {
+    fn list_zones(&mut self) -> Result<Vec<String>, ProviderError> {
+        Ok(self.zones.clone())
+    }
+
+    fn find_zone(&mut self, domain: &str) -> Result<(String, String), ProviderError> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+
+        // the most specific zone wins if zones are nested
+        self.zones
+            .iter()
+            .filter(|zone| domain == **zone || domain.ends_with(&format!(".{}", zone)))
+            .max_by_key(|zone| zone.len())
+            .map(|zone| {
+                let name = domain[..domain.len() - zone.len()].trim_end_matches('.');
+
+                (zone.to_owned(), name.to_owned())
+            })
+            .ok_or(ProviderError::DomainNotFound)
+    }
+
+    fn create_record(
+        &mut self,
+        domain: &str,
+        record_type: &str,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), ProviderError> {
+        self.find_zone(domain)?;
+
+        let mut records = self.read()?;
+        let id = records.iter().map(|record| record.id).max().unwrap_or(0) + 1;
+
+        records.push(Record {
+            id,
+            name: domain.trim_end_matches('.').to_lowercase(),
+            record_type: record_type.to_uppercase(),
+            content: content.to_owned(),
+            ttl: ttl.unwrap_or(DEFAULT_TTL),
+            prio: prio.unwrap_or(0),
+        });
+
+        self.write(&records)
+    }
+
+    fn get_records(
+        &mut self,
+        domain: &str,
+        record_type: Option<&str>,
+        content: Option<&str>,
+    ) -> Result<Vec<Record>, ProviderError> {
+        self.find_zone(domain)?;
+
+        let domain = domain.trim_end_matches('.');
+
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter(|record| record.name.eq_ignore_ascii_case(domain))
+            .filter(|record| {
+                record_type
+                    .is_none_or(|record_type| record.record_type.eq_ignore_ascii_case(record_type))
+            })
+            .filter(|record| content.is_none_or(|content| record.content == content))
+            .collect())
+    }
+
+    fn list_records(&mut self, zone: &str) -> Result<Vec<Record>, ProviderError> {
+        let zone = zone.trim_end_matches('.').to_lowercase();
+
+        if !self.zones.contains(&zone) {
+            return Err(ProviderError::DomainNotFound);
+        }
+
+        // records of nested zones belong to those
+        let nested: Vec<&String> = self
+            .zones
+            .iter()
+            .filter(|other| other.ends_with(&format!(".{}", zone)))
+            .collect();
+
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter(|record| Self::in_zone(record, &zone))
+            .filter(|record| !nested.iter().any(|other| Self::in_zone(record, other)))
+            .collect())
+    }
+
+    fn update_record(
+        &mut self,
+        id: i32,
+        content: &str,
+        ttl: Option<i32>,
+        prio: Option<i32>,
+    ) -> Result<(), ProviderError> {
+        let mut records = self.read()?;
+        let record = records
+            .iter_mut()
+            .find(|record| record.id == id)
+            .ok_or(ProviderError::RecordNotFound)?;
+
+        record.content = content.to_owned();
+        record.ttl = ttl.unwrap_or(record.ttl);
+        record.prio = prio.unwrap_or(record.prio);
+
+        self.write(&records)
+    }
+
+    fn delete_record(&mut self, id: i32) -> Result<(), ProviderError> {
+        let mut records = self.read()?;
+        let count = records.len();
+
+        records.retain(|record| record.id != id);
+
+        if records.len() == count {
+            return Err(ProviderError::RecordNotFound);
+        }
+
+        self.write(&records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// A provider of example.com and its nested zone sub.example.com with a new records file.
+    fn provider(name: &str) -> (FileProvider, TempDir) {
+        let directory = TempDir::new(&format!("records-{}", name));
+
+        let provider = FileProvider::new(&RecordsFile {
+            path: directory
+                .join("records.json")
+                .to_string_lossy()
+                .into_owned(),
+            zones: vec!["Example.com.".to_owned(), "sub.example.com".to_owned()],
+        });
+
+        (provider, directory)
+    }
+
+    #[test]
+    fn find_zone() {
+        let (mut provider, _directory) = provider("find-zone");
+
+        assert_eq!(
+            provider
+                .find_zone("_acme-challenge.www.example.com.")
+                .unwrap(),
+            ("example.com".to_owned(), "_acme-challenge.www".to_owned())
+        );
+        assert_eq!(
+            provider.find_zone("www.Sub.example.com").unwrap(),
+            ("sub.example.com".to_owned(), "www".to_owned())
+        );
+        assert_eq!(
+            provider.find_zone("example.com").unwrap(),
+            ("example.com".to_owned(), String::new())
+        );
+        assert!(matches!(
+            provider.find_zone("example.org"),
+            Err(ProviderError::DomainNotFound)
+        ));
+    }
+
+    #[test]
+    fn create_list_delete() {
+        let (mut provider, _directory) = provider("round-trip");
+
+        provider
+            .create_txt_record("_acme-challenge.example.com", "first")
+            .unwrap();
+        provider
+            .create_record("example.com", "mx", "mail.example.com", Some(300), Some(10))
+            .unwrap();
+        provider
+            .create_txt_record("_acme-challenge.Sub.example.com", "second")
+            .unwrap();
+
+        let records = provider.list_records("example.com").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].content, "first");
+        assert_eq!(records[0].ttl, DEFAULT_TTL);
+        assert_eq!(records[1].record_type, "MX");
+        assert_eq!((records[1].ttl, records[1].prio), (300, 10));
+
+        // the records of the nested zone are not part of the parent
+        let records = provider.list_records("sub.example.com").unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "_acme-challenge.sub.example.com");
+
+        let id = records[0].id;
+        provider.update_record(id, "updated", None, None).unwrap();
+        assert_eq!(
+            provider
+                .get_records(
+                    "_acme-challenge.sub.example.com",
+                    Some("txt"),
+                    Some("updated")
+                )
+                .unwrap()[0]
+                .id,
+            id
+        );
+
+        provider
+            .delete_txt_record("_acme-challenge.example.com", Some("first"))
+            .unwrap();
+        assert!(matches!(
+            provider.delete_txt_record("_acme-challenge.example.com", Some("first")),
+            Err(ProviderError::RecordNotFound)
+        ));
+        assert!(matches!(
+            provider.delete_record(id + 100),
+            Err(ProviderError::RecordNotFound)
+        ));
+
+        assert_eq!(provider.list_records("example.com").unwrap().len(), 1);
+        assert!(matches!(
+            provider.create_txt_record("example.org", "value"),
+            Err(ProviderError::DomainNotFound)
+        ));
+    }
+}
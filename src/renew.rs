@@ -0,0 +1,342 @@
+use crate::acme::{AcmeClient, DnsChallenge};
+use crate::certificate::{check_certificate, generate_key, load_account_key, write_certificate};
+use crate::challenge::{create_record, delete, wait_for_propagation, Sessions};
+use crate::config::{Certificate, Config};
+use crate::dns::lookup_real_domain;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Skipped,
+    Renewed,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenewResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub message: String,
+}
+
+fn validate_challenges(
+    config: &Config,
+    sessions: &mut Sessions,
+    acme: &mut AcmeClient,
+    challenges: &[DnsChallenge],
+    created: &mut Vec<(String, String)>,
+) -> Result<(), ()> {
+    let mut records = Vec::new();
+
+    for challenge in challenges {
+        let domain = lookup_real_domain(
+            &config.options_for(&challenge.name).dns_server,
+            &challenge.name,
+        );
+
+        if !create_record(config, sessions, &domain, &challenge.value)? {
+            records.push((domain.to_owned(), challenge.value.to_owned()));
+        }
+
+        created.push((domain, challenge.value.to_owned()));
+    }
+
+    wait_for_propagation(config, &records)?;
+
+    for challenge in challenges {
+        info!("Validating {}...", challenge.domain);
+        acme.validate(challenge).map_err(|e| error!("{}", e))?;
+        info!("=> done!");
+    }
+
+    Ok(())
+}
+
+fn issue_certificate(
+    config: &Config,
+    sessions: &mut Sessions,
+    acme: &mut AcmeClient,
+    certificate: &Certificate,
+) -> Result<(), ()> {
+    let order = acme
+        .new_order(&certificate.domains)
+        .map_err(|e| error!("{}", e))?;
+    let challenges = acme.dns_challenges(&order).map_err(|e| error!("{}", e))?;
+
+    let mut created = Vec::new();
+    let result = validate_challenges(config, sessions, acme, &challenges, &mut created);
+
+    for (domain, value) in created {
+        // a failed cleanup is already logged and must not prevent the certificate from being issued
+        let _ = delete(config, sessions, &domain, Some(&value));
+    }
+
+    result?;
+
+    info!("Requesting certificate...");
+
+    let key = generate_key(certificate.key_type)
+        .map_err(|e| error!("Failed to generate private key: {}", e))?;
+    let chain = acme
+        .finalize(&order, &certificate.domains, &key)
+        .map_err(|e| error!("{}", e))?;
+    write_certificate(certificate, &key, &chain).map_err(|e| error!("{}", e))?;
+
+    info!("=> done!");
+
+    Ok(())
+}
+
+fn deploy_certificate(certificate: &Certificate) -> Result<(), ()> {
+    let command = match certificate.deploy_command {
+        Some(ref command) => command,
+        None => return Ok(()),
+    };
+
+    info!("Running deploy command for {}...", certificate.name);
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("LETSENCRYPT_INWX_CERT_NAME", &certificate.name)
+        .env("LETSENCRYPT_INWX_CERT_PATH", &certificate.cert_path)
+        .env("LETSENCRYPT_INWX_KEY_PATH", &certificate.key_path)
+        .env("LETSENCRYPT_INWX_DOMAINS", certificate.domains.join(" "))
+        .status()
+        .map_err(|e| error!("Failed to run deploy command: {}", e))?;
+
+    if !status.success() {
+        error!("The deploy command failed with {}", status);
+        return Err(());
+    }
+
+    info!("=> done!");
+
+    Ok(())
+}
+
+fn acme_client(config: &Config) -> Result<AcmeClient, ()> {
+    let key = load_account_key(&config.acme.account_key).map_err(|e| error!("{}", e))?;
+    let mut acme = AcmeClient::new(&config.acme.directory, key, &config.options)
+        .map_err(|e| error!("{}", e))?;
+    acme.register(config.acme.email.as_deref())
+        .map_err(|e| error!("{}", e))?;
+
+    Ok(acme)
+}
+
+/// Issues every selected certificate which is due for renewal (or all of them if `force` is set).
+pub fn renew_certificates(
+    config: &Config,
+    sessions: &mut Sessions,
+    names: &[&str],
+    force: bool,
+) -> Vec<RenewResult> {
+    let mut acme = None;
+    let mut results = Vec::new();
+
+    for certificate in config
+        .certificates
+        .iter()
+        .filter(|c| names.is_empty() || names.contains(&c.name.as_str()))
+    {
+        let renew_before_days = certificate
+            .renew_before_days
+            .unwrap_or(config.acme.renew_before_days);
+
+        let result = |outcome, message: String| RenewResult {
+            name: certificate.name.to_owned(),
+            outcome,
+            message,
+        };
+
+        let status = match check_certificate(certificate, renew_before_days) {
+            Ok(status) => status,
+            Err(e) => {
+                error!("Certificate {}: {}", certificate.name, e);
+                results.push(result(Outcome::Failed, e.to_string()));
+                continue;
+            }
+        };
+
+        info!("Certificate {} {}", certificate.name, status);
+
+        if !force && !status.needs_renewal() {
+            results.push(result(Outcome::Skipped, status.to_string()));
+            continue;
+        }
+
+        info!("Issuing certificate {}...", certificate.name);
+
+        if acme.is_none() {
+            acme = acme_client(config).ok();
+        }
+
+        let outcome = match acme {
+            Some(ref mut acme) => issue_certificate(config, sessions, acme, certificate)
+                .map(|_| "issued")
+                .map_err(|_| "failed to issue the certificate")
+                .and_then(|message| {
+                    deploy_certificate(certificate)
+                        .map(|_| message)
+                        .map_err(|_| "failed to deploy the certificate")
+                }),
+            None => Err("failed to set up the acme account"),
+        };
+
+        results.push(match outcome {
+            Ok(message) => result(Outcome::Renewed, message.to_owned()),
+            Err(message) => result(Outcome::Failed, message.to_owned()),
+        });
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificate::generate_key;
+    use crate::config::KeyType;
+    use crate::test_util::{self, spawn_server, TempDir};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use serde_json::json;
+    use std::fs;
+    use std::path::Path;
+    use tiny_http::Response;
+
+    fn write_self_signed(path: &Path, domains: &[&str], days: u32) {
+        let key = generate_key(KeyType::Ec256).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", domains[0]).unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+
+        let mut alt_names = SubjectAlternativeName::new();
+        for domain in domains {
+            alt_names.dns(domain);
+        }
+        let alt_names = alt_names
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(alt_names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        fs::write(path, builder.build().to_pem().unwrap()).unwrap();
+    }
+
+    /// A config with the certificates in a new directory, the acme directory can't be fetched.
+    fn config(directory: &TempDir) -> &'static Config {
+        let url = spawn_server(|server| {
+            for request in server.incoming_requests() {
+                let _ = request.respond(Response::empty(404));
+            }
+        });
+
+        let certificate = |name: &str, domains: &[&str], renew_before_days: Option<u32>| {
+            json!({
+                "name": name,
+                "domains": domains,
+                "cert_path": directory.join(&format!("{}.pem", name)),
+                "key_path": directory.join(&format!("{}.key", name)),
+                "renew_before_days": renew_before_days,
+            })
+        };
+
+        test_util::config(json!({
+            "records_file": {
+                "path": directory.join("records.json"),
+                "zones": ["example.com"],
+            },
+            "options": { "no_proxy": "*" },
+            "acme": {
+                "directory": format!("{}/directory", url),
+                "account_key": directory.join("account.key"),
+                "renew_before_days": 30,
+            },
+            "certificates": [
+                certificate("valid", &["example.com", "*.example.com"], None),
+                certificate("expiring", &["example.com"], Some(60)),
+                certificate("changed", &["example.com", "www.example.com"], None),
+                certificate("missing", &["example.com"], None),
+                certificate("invalid", &["example.com"], None),
+            ],
+        }))
+    }
+
+    fn outcomes(results: &[RenewResult]) -> Vec<(&str, Outcome)> {
+        results
+            .iter()
+            .map(|result| (result.name.as_str(), result.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn renew_due_certificates() {
+        let directory = TempDir::new("renew");
+        let config = config(&directory);
+
+        write_self_signed(
+            &directory.join("valid.pem"),
+            &["*.example.com", "example.com"],
+            90,
+        );
+        write_self_signed(&directory.join("expiring.pem"), &["example.com"], 45);
+        write_self_signed(&directory.join("changed.pem"), &["example.com"], 90);
+        fs::write(directory.join("invalid.pem"), "not a certificate").unwrap();
+
+        let results = renew_certificates(config, &mut Sessions::new(), &[], false);
+
+        // the certificates due for renewal fail because the acme directory is unavailable
+        assert_eq!(
+            outcomes(&results),
+            vec![
+                ("valid", Outcome::Skipped),
+                ("expiring", Outcome::Failed),
+                ("changed", Outcome::Failed),
+                ("missing", Outcome::Failed),
+                ("invalid", Outcome::Failed),
+            ]
+        );
+        assert_eq!(results[1].message, "failed to set up the acme account");
+        assert_ne!(results[4].message, "failed to set up the acme account");
+
+        let skipped = serde_json::to_value(&results[0]).unwrap();
+        assert_eq!(skipped["outcome"], "skipped");
+        assert!(skipped["message"].as_str().unwrap().contains("valid"));
+    }
+
+    #[test]
+    fn select_and_force() {
+        let directory = TempDir::new("renew-force");
+        let config = config(&directory);
+
+        write_self_signed(
+            &directory.join("valid.pem"),
+            &["example.com", "*.example.com"],
+            90,
+        );
+
+        let results = renew_certificates(config, &mut Sessions::new(), &["valid"], false);
+        assert_eq!(outcomes(&results), vec![("valid", Outcome::Skipped)]);
+
+        let results = renew_certificates(config, &mut Sessions::new(), &["valid"], true);
+        assert_eq!(outcomes(&results), vec![("valid", Outcome::Failed)]);
+    }
+}
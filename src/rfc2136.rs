@@ -0,0 +1,1255 @@
+use crate::auth::{audit, Client};
+use crate::challenge::{execute_api_commands, Sessions};
+use crate::config::{Config, TsigKey};
+use crate::inwx::MIN_TTL;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+const TYPE_MX: u16 = 15;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_TSIG: u16 = 250;
+const TYPE_ANY: u16 = 255;
+const TYPE_CAA: u16 = 257;
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+const OPCODE_QUERY: u8 = 0;
+const OPCODE_UPDATE: u8 = 5;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+const RCODE_NOTAUTH: u8 = 9;
+const RCODE_NOTZONE: u8 = 10;
+
+const TSIG_BADSIG: u16 = 16;
+const TSIG_BADKEY: u16 = 17;
+const TSIG_BADTIME: u16 = 18;
+
+#[derive(Debug)]
+struct FormatError;
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn read_name(message: &[u8], mut pos: usize) -> Result<(String, usize), FormatError> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *message.get(pos).ok_or(FormatError)? as usize;
+
+        if len & 0xc0 == 0xc0 {
+            let low = *message.get(pos + 1).ok_or(FormatError)? as usize;
+
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+
+            jumps += 1;
+            if jumps > 64 {
+                return Err(FormatError);
+            }
+
+            pos = ((len & 0x3f) << 8) | low;
+        } else if len == 0 {
+            if end.is_none() {
+                end = Some(pos + 1);
+            }
+
+            break;
+        } else {
+            let label = message.get(pos + 1..pos + 1 + len).ok_or(FormatError)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += 1 + len;
+        }
+    }
+
+    Ok((labels.join("."), end.unwrap()))
+}
+
+struct Reader<'a> {
+    message: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], FormatError> {
+        let bytes = self
+            .message
+            .get(self.pos..self.pos + len)
+            .ok_or(FormatError)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn remaining(&self) -> usize {
+        self.message.len() - self.pos
+    }
+
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u48(&mut self) -> Result<u64, FormatError> {
+        Ok((u64::from(self.u16()?) << 32) | u64::from(self.u32()?))
+    }
+
+    fn name(&mut self) -> Result<String, FormatError> {
+        let (name, end) = read_name(self.message, self.pos)?;
+        self.pos = end;
+        Ok(name)
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(&value.to_be_bytes());
+    }
+
+    fn u48(&mut self, value: u64) {
+        self.bytes.extend(&value.to_be_bytes()[2..]);
+    }
+
+    /// Writes the name in the uncompressed canonical form.
+    fn name(&mut self, name: &str) {
+        for label in name.split('.').filter(|label| !label.is_empty()) {
+            self.u8(label.len() as u8);
+            self.bytes.extend(label.to_lowercase().as_bytes());
+        }
+
+        self.u8(0);
+    }
+
+    fn record(&mut self, name: &str, record_type: u16, class: u16, ttl: u32, rdata: &[u8]) {
+        self.name(name);
+        self.u16(record_type);
+        self.u16(class);
+        self.u32(ttl);
+        self.u16(rdata.len() as u16);
+        self.bytes.extend(rdata);
+    }
+}
+
+struct Question {
+    name: String,
+    record_type: u16,
+    class: u16,
+}
+
+struct ResourceRecord {
+    start: usize,
+    name: String,
+    record_type: u16,
+    class: u16,
+    ttl: u32,
+    rdata_start: usize,
+    rdata_len: usize,
+}
+
+struct Message<'a> {
+    bytes: &'a [u8],
+    id: u16,
+    opcode: u8,
+    questions: Vec<Question>,
+    answers: Vec<ResourceRecord>,
+    authority: Vec<ResourceRecord>,
+    additional: Vec<ResourceRecord>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Message<'a>, FormatError> {
+        let mut reader = Reader {
+            message: bytes,
+            pos: 0,
+        };
+
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut questions = Vec::new();
+        for _ in 0..counts[0] {
+            questions.push(Question {
+                name: reader.name()?,
+                record_type: reader.u16()?,
+                class: reader.u16()?,
+            });
+        }
+
+        let mut sections = Vec::new();
+        for count in &counts[1..] {
+            let mut records = Vec::new();
+
+            for _ in 0..*count {
+                let start = reader.pos;
+                let name = reader.name()?;
+                let record_type = reader.u16()?;
+                let class = reader.u16()?;
+                let ttl = reader.u32()?;
+                let rdata_len = reader.u16()? as usize;
+                let rdata_start = reader.pos;
+                reader.bytes(rdata_len)?;
+
+                records.push(ResourceRecord {
+                    start,
+                    name,
+                    record_type,
+                    class,
+                    ttl,
+                    rdata_start,
+                    rdata_len,
+                });
+            }
+
+            sections.push(records);
+        }
+
+        let additional = sections.pop().unwrap();
+        let authority = sections.pop().unwrap();
+        let answers = sections.pop().unwrap();
+
+        Ok(Message {
+            bytes,
+            id,
+            opcode: ((flags >> 11) & 0x0f) as u8,
+            questions,
+            answers,
+            authority,
+            additional,
+        })
+    }
+
+    /// Returns a reader of the rdata, which can't read past it (but follows name pointers into
+    /// the preceding part of the message).
+    fn rdata(&self, record: &ResourceRecord) -> Reader<'a> {
+        Reader {
+            message: &self.bytes[..record.rdata_start + record.rdata_len],
+            pos: record.rdata_start,
+        }
+    }
+}
+
+struct Tsig {
+    start: usize,
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+}
+
+impl Tsig {
+    /// Returns the TSIG record, which has to be the last record of the message.
+    fn parse(message: &Message) -> Result<Option<Tsig>, FormatError> {
+        let record = match message.additional.last() {
+            Some(record) if record.record_type == TYPE_TSIG => record,
+            _ => return Ok(None),
+        };
+
+        let mut rdata = message.rdata(record);
+        let algorithm = rdata.name()?;
+        let time_signed = rdata.u48()?;
+        let fudge = rdata.u16()?;
+        let mac_len = rdata.u16()? as usize;
+        let mac = rdata.bytes(mac_len)?.to_vec();
+        let original_id = rdata.u16()?;
+
+        Ok(Some(Tsig {
+            start: record.start,
+            key_name: record.name.to_owned(),
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+        }))
+    }
+}
+
+fn digest(algorithm: &str) -> Option<MessageDigest> {
+    match algorithm.trim_end_matches('.').to_lowercase().as_str() {
+        "hmac-sha1" => Some(MessageDigest::sha1()),
+        "hmac-sha256" => Some(MessageDigest::sha256()),
+        "hmac-sha384" => Some(MessageDigest::sha384()),
+        "hmac-sha512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+fn hmac(digest: MessageDigest, secret: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let key = PKey::hmac(secret).ok()?;
+    let mut signer = Signer::new(digest, &key).ok()?;
+    signer.update(data).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+/// Appends the TSIG variables of RFC 8945 section 4.3.3 which are covered by the mac.
+fn tsig_variables(
+    data: &mut Writer,
+    key_name: &str,
+    algorithm: &str,
+    time: u64,
+    fudge: u16,
+    error: u16,
+    other: &[u8],
+) {
+    data.name(key_name);
+    data.u16(CLASS_ANY);
+    data.u32(0);
+    data.name(algorithm);
+    data.u48(time);
+    data.u16(fudge);
+    data.u16(error);
+    data.u16(other.len() as u16);
+    data.bytes.extend(other);
+}
+
+struct Response {
+    id: u16,
+    opcode: u8,
+    rcode: u8,
+    authoritative: bool,
+    question: Option<(String, u16, u16)>,
+    answers: Writer,
+    answer_count: u16,
+    authority: Writer,
+    authority_count: u16,
+}
+
+impl Response {
+    fn new(message: &Message, rcode: u8) -> Response {
+        Response {
+            id: message.id,
+            opcode: message.opcode,
+            rcode,
+            authoritative: false,
+            question: message.questions.first().map(|question| {
+                (
+                    question.name.to_owned(),
+                    question.record_type,
+                    question.class,
+                )
+            }),
+            answers: Writer::default(),
+            answer_count: 0,
+            authority: Writer::default(),
+            authority_count: 0,
+        }
+    }
+
+    fn header_only(request: &[u8], rcode: u8) -> Vec<u8> {
+        let mut response = Writer::default();
+        response.bytes.extend(&request[0..2]);
+        response.u16(0x8000 | (u16::from(request[2] & 0x78) << 8) | u16::from(rcode));
+        response.bytes.extend(&[0; 8]);
+        response.bytes
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        let mut flags = 0x8000 | (u16::from(self.opcode) << 11) | u16::from(self.rcode);
+
+        if self.authoritative {
+            flags |= 0x0400;
+        }
+
+        writer.u16(self.id);
+        writer.u16(flags);
+        writer.u16(self.question.is_some() as u16);
+        writer.u16(self.answer_count);
+        writer.u16(self.authority_count);
+        writer.u16(0);
+
+        if let Some((ref name, record_type, class)) = self.question {
+            writer.name(name);
+            writer.u16(record_type);
+            writer.u16(class);
+        }
+
+        writer.bytes.extend(&self.answers.bytes);
+        writer.bytes.extend(&self.authority.bytes);
+        writer.bytes
+    }
+}
+
+/// An inwx record change requested by an update message.
+enum Change {
+    Add {
+        name: String,
+        record_type: &'static str,
+        content: String,
+        ttl: u32,
+        prio: Option<i32>,
+    },
+    DeleteName {
+        name: String,
+    },
+    DeleteRecordSet {
+        name: String,
+        record_type: &'static str,
+    },
+    DeleteRecord {
+        name: String,
+        record_type: &'static str,
+        content: String,
+    },
+}
+
+impl Change {
+    /// Returns the action, name and record type written to the audit log.
+    fn audit_entry(&self) -> (&str, &str, &str) {
+        match self {
+            Change::Add {
+                name, record_type, ..
+            } => ("add", name, record_type),
+            Change::DeleteName { name } => ("delete", name, "ANY"),
+            Change::DeleteRecordSet { name, record_type }
+            | Change::DeleteRecord {
+                name, record_type, ..
+            } => ("delete", name, record_type),
+        }
+    }
+}
+
+fn type_name(record_type: u16) -> Option<&'static str> {
+    match record_type {
+        TYPE_A => Some("A"),
+        TYPE_NS => Some("NS"),
+        TYPE_CNAME => Some("CNAME"),
+        TYPE_PTR => Some("PTR"),
+        TYPE_MX => Some("MX"),
+        TYPE_TXT => Some("TXT"),
+        TYPE_AAAA => Some("AAAA"),
+        TYPE_SRV => Some("SRV"),
+        TYPE_CAA => Some("CAA"),
+        _ => None,
+    }
+}
+
+/// Converts the rdata into the content and priority used by the inwx api.
+fn rdata_to_content(
+    message: &Message,
+    record: &ResourceRecord,
+) -> Result<(String, Option<i32>), FormatError> {
+    let mut rdata = message.rdata(record);
+
+    let content = match record.record_type {
+        TYPE_A => {
+            let bytes = rdata.bytes(4)?;
+            (
+                Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).to_string(),
+                None,
+            )
+        }
+        TYPE_AAAA => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(rdata.bytes(16)?);
+            (Ipv6Addr::from(octets).to_string(), None)
+        }
+        TYPE_NS | TYPE_CNAME | TYPE_PTR => (rdata.name()?, None),
+        TYPE_MX => {
+            let prio = rdata.u16()?;
+            (rdata.name()?, Some(i32::from(prio)))
+        }
+        TYPE_TXT => {
+            let mut content = Vec::new();
+
+            while rdata.remaining() > 0 {
+                let len = rdata.u8()? as usize;
+                content.extend(rdata.bytes(len)?);
+            }
+
+            (String::from_utf8_lossy(&content).into_owned(), None)
+        }
+        TYPE_SRV => {
+            let prio = rdata.u16()?;
+            let weight = rdata.u16()?;
+            let port = rdata.u16()?;
+            let target = rdata.name()?;
+            (
+                format!("{} {} {}", weight, port, target),
+                Some(i32::from(prio)),
+            )
+        }
+        TYPE_CAA => {
+            let flags = rdata.u8()?;
+            let tag_len = rdata.u8()? as usize;
+            let tag = String::from_utf8_lossy(rdata.bytes(tag_len)?).into_owned();
+            let value = String::from_utf8_lossy(rdata.bytes(rdata.remaining())?).into_owned();
+            (format!("{} {} \"{}\"", flags, tag, value), None)
+        }
+        _ => return Err(FormatError),
+    };
+
+    if rdata.remaining() != 0 {
+        return Err(FormatError);
+    }
+
+    Ok(content)
+}
+
+fn in_zone(name: &str, zone: &str) -> bool {
+    name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+struct Rfc2136Server<'a> {
+    config: &'a Config,
+    sessions: Mutex<Sessions>,
+}
+
+impl<'a> Rfc2136Server<'a> {
+    fn find_key(&self, tsig: &Tsig) -> Result<(&'a TsigKey, MessageDigest, Vec<u8>), u16> {
+        let key = self
+            .config
+            .rfc2136
+            .keys
+            .iter()
+            .find(|key| {
+                key.name
+                    .trim_end_matches('.')
+                    .eq_ignore_ascii_case(&tsig.key_name)
+                    && key
+                        .algorithm
+                        .trim_end_matches('.')
+                        .eq_ignore_ascii_case(&tsig.algorithm)
+            })
+            .ok_or(TSIG_BADKEY)?;
+
+        let digest = digest(&key.algorithm).ok_or_else(|| {
+            error!(
+                "Unsupported TSIG algorithm {} of key {}",
+                key.algorithm, key.name
+            );
+            TSIG_BADKEY
+        })?;
+
+        let secret = base64::decode(key.secret.expose()).map_err(|_| {
+            error!(
+                "The secret of the TSIG key {} is not valid base64",
+                key.name
+            );
+            TSIG_BADKEY
+        })?;
+
+        Ok((key, digest, secret))
+    }
+
+    fn verify(
+        &self,
+        message: &Message,
+        tsig: &Tsig,
+        now: u64,
+    ) -> Result<(&'a TsigKey, MessageDigest, Vec<u8>), u16> {
+        let (key, digest, secret) = self.find_key(tsig)?;
+
+        // the mac covers the message without the TSIG record and with the original id
+        let mut data = Writer::default();
+        data.bytes.extend(&message.bytes[..tsig.start]);
+        data.bytes[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let additional_count = u16::from_be_bytes([data.bytes[10], data.bytes[11]]) - 1;
+        data.bytes[10..12].copy_from_slice(&additional_count.to_be_bytes());
+        tsig_variables(
+            &mut data,
+            &tsig.key_name,
+            &tsig.algorithm,
+            tsig.time_signed,
+            tsig.fudge,
+            0,
+            &[],
+        );
+
+        let mac = hmac(digest, &secret, &data.bytes).ok_or(TSIG_BADSIG)?;
+
+        if mac.len() != tsig.mac.len() || !memcmp::eq(&mac, &tsig.mac) {
+            return Err(TSIG_BADSIG);
+        }
+
+        if now.max(tsig.time_signed) - now.min(tsig.time_signed) > u64::from(tsig.fudge) {
+            return Err(TSIG_BADTIME);
+        }
+
+        Ok((key, digest, secret))
+    }
+
+    /// Appends a TSIG record to the response, it is only signed if `signing` is set.
+    fn sign(
+        &self,
+        response: &mut Vec<u8>,
+        tsig: &Tsig,
+        signing: Option<(MessageDigest, &[u8])>,
+        error: u16,
+        now: u64,
+    ) {
+        let other = match error {
+            TSIG_BADTIME => {
+                let mut other = Writer::default();
+                other.u48(now);
+                other.bytes
+            }
+            _ => vec![],
+        };
+
+        let mac = match signing {
+            Some((digest, secret)) => {
+                let mut data = Writer::default();
+                data.u16(tsig.mac.len() as u16);
+                data.bytes.extend(&tsig.mac);
+                data.bytes.extend(response.iter());
+                tsig_variables(
+                    &mut data,
+                    &tsig.key_name,
+                    &tsig.algorithm,
+                    now,
+                    tsig.fudge,
+                    error,
+                    &other,
+                );
+
+                hmac(digest, secret, &data.bytes).unwrap_or_default()
+            }
+            None => vec![],
+        };
+
+        let mut rdata = Writer::default();
+        rdata.name(&tsig.algorithm);
+        rdata.u48(now);
+        rdata.u16(tsig.fudge);
+        rdata.u16(mac.len() as u16);
+        rdata.bytes.extend(&mac);
+        rdata.bytes.extend(&response[0..2]);
+        rdata.u16(error);
+        rdata.u16(other.len() as u16);
+        rdata.bytes.extend(&other);
+
+        let mut record = Writer::default();
+        record.record(&tsig.key_name, TYPE_TSIG, CLASS_ANY, 0, &rdata.bytes);
+        response.extend(record.bytes);
+
+        let additional_count = u16::from_be_bytes([response[10], response[11]]) + 1;
+        response[10..12].copy_from_slice(&additional_count.to_be_bytes());
+    }
+
+    fn find_zone(&self, name: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut zone = None;
+
+        execute_api_commands(self.config, &mut sessions, name, |api| {
+            zone = Some(api.find_zone(name)?.0);
+            Ok(())
+        })
+        .ok()?;
+
+        zone
+    }
+
+    fn soa_record(zone: &str) -> Writer {
+        // only the owner of the SOA record matters for update clients, so the data is static
+        let mut rdata = Writer::default();
+        rdata.name("ns.inwx.de");
+        rdata.name("hostmaster.inwx.de");
+        rdata.u32(1);
+        rdata.u32(10800);
+        rdata.u32(3600);
+        rdata.u32(604800);
+        rdata.u32(3600);
+
+        let mut record = Writer::default();
+        record.record(zone, TYPE_SOA, CLASS_IN, 3600, &rdata.bytes);
+        record
+    }
+
+    /// Answers SOA queries, which clients like certbot-dns-rfc2136 use to find the zone.
+    fn query(&self, message: &Message, key: &TsigKey) -> Response {
+        let question = match message.questions.as_slice() {
+            [question] if question.class == CLASS_IN => question,
+            _ => return Response::new(message, RCODE_FORMERR),
+        };
+
+        if question.record_type != TYPE_SOA {
+            return Response::new(message, RCODE_NOTIMP);
+        }
+
+        // the zones outside of the scope of the key are not revealed
+        let zone = match self.find_zone(&question.name) {
+            Some(zone) if key.zones.iter().any(|allowed| in_zone(&zone, allowed)) => zone,
+            _ => return Response::new(message, RCODE_REFUSED),
+        };
+
+        let mut response = Response::new(message, RCODE_NOERROR);
+        response.authoritative = true;
+
+        if zone == question.name {
+            response.answers = Self::soa_record(&zone);
+            response.answer_count = 1;
+        } else {
+            response.authority = Self::soa_record(&zone);
+            response.authority_count = 1;
+        }
+
+        response
+    }
+
+    fn parse_changes(&self, message: &Message, zone: &str) -> Result<Vec<Change>, u8> {
+        let mut changes = Vec::new();
+
+        for record in &message.authority {
+            if !in_zone(&record.name, zone) {
+                return Err(RCODE_NOTZONE);
+            }
+
+            let name = record.name.to_owned();
+
+            let change = match (record.class, record.record_type) {
+                (CLASS_ANY, TYPE_ANY) if record.rdata_len == 0 => Change::DeleteName { name },
+                (CLASS_ANY, record_type) if record.rdata_len == 0 => Change::DeleteRecordSet {
+                    name,
+                    record_type: type_name(record_type).ok_or(RCODE_NOTIMP)?,
+                },
+                (CLASS_NONE, record_type) => Change::DeleteRecord {
+                    name,
+                    record_type: type_name(record_type).ok_or(RCODE_NOTIMP)?,
+                    content: rdata_to_content(message, record)
+                        .map_err(|_| RCODE_FORMERR)?
+                        .0,
+                },
+                (CLASS_IN, record_type) => {
+                    let record_type = type_name(record_type).ok_or(RCODE_NOTIMP)?;
+                    let (content, prio) =
+                        rdata_to_content(message, record).map_err(|_| RCODE_FORMERR)?;
+
+                    Change::Add {
+                        name,
+                        record_type,
+                        content,
+                        ttl: record.ttl,
+                        prio,
+                    }
+                }
+                _ => return Err(RCODE_FORMERR),
+            };
+
+            changes.push(change);
+        }
+
+        Ok(changes)
+    }
+
+    fn apply(&self, zone: &str, change: &Change) -> Result<(), ()> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match change {
+            Change::Add {
+                name,
+                record_type,
+                content,
+                ttl,
+                prio,
+            } => {
+                info!("Adding {} record {} {}", record_type, name, content);
+
+                execute_api_commands(self.config, &mut sessions, name, |api| {
+                    // adding an existing record is not an error in RFC 2136
+                    if api
+                        .get_records(name, Some(record_type), Some(content))?
+                        .is_empty()
+                    {
+                        // inwx rejects lower ttls, the usual ones of certbot and lego are 60 to 120
+                        let ttl = (*ttl).clamp(MIN_TTL as u32, i32::MAX as u32) as i32;
+
+                        api.create_record(name, record_type, content, Some(ttl), *prio)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Change::DeleteName { name } => {
+                info!("Deleting all records of {}", name);
+
+                execute_api_commands(self.config, &mut sessions, name, |api| {
+                    for record in api.get_records(name, None, None)? {
+                        // the SOA and NS records of the zone are never deleted
+                        if name == zone
+                            && (record.record_type == "SOA" || record.record_type == "NS")
+                        {
+                            continue;
+                        }
+
+                        api.delete_record(record.id)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Change::DeleteRecordSet { name, record_type } => {
+                info!("Deleting all {} records of {}", record_type, name);
+
+                execute_api_commands(self.config, &mut sessions, name, |api| {
+                    if name == zone && *record_type == "NS" {
+                        return Ok(());
+                    }
+
+                    for record in api.get_records(name, Some(record_type), None)? {
+                        api.delete_record(record.id)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Change::DeleteRecord {
+                name,
+                record_type,
+                content,
+            } => {
+                info!("Deleting {} record {} {}", record_type, name, content);
+
+                execute_api_commands(self.config, &mut sessions, name, |api| {
+                    for record in api.get_records(name, Some(record_type), Some(content))? {
+                        api.delete_record(record.id)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update(&self, message: &Message, key: &TsigKey) -> Response {
+        let zone = match message.questions.as_slice() {
+            [zone] if zone.record_type == TYPE_SOA && zone.class == CLASS_IN => &zone.name,
+            _ => return Response::new(message, RCODE_FORMERR),
+        };
+
+        if !message.answers.is_empty() {
+            warn!("Update prerequisites are not supported");
+            return Response::new(message, RCODE_NOTIMP);
+        }
+
+        let changes = match self.parse_changes(message, zone) {
+            Ok(changes) => changes,
+            Err(rcode) => return Response::new(message, rcode),
+        };
+
+        let allowed = key.zones.iter().any(|allowed| in_zone(zone, allowed));
+
+        for change in &changes {
+            let (action, name, record_type) = change.audit_entry();
+            audit(
+                self.config,
+                &Client::Unrestricted(&key.name),
+                action,
+                name,
+                record_type,
+                allowed,
+            );
+        }
+
+        if !allowed {
+            warn!(
+                "The key {} is not allowed to update the zone {}",
+                key.name, zone
+            );
+            return Response::new(message, RCODE_REFUSED);
+        }
+
+        if self.find_zone(zone).as_ref() != Some(zone) {
+            warn!("{} is not a zone hosted by inwx", zone);
+            return Response::new(message, RCODE_NOTAUTH);
+        }
+
+        for change in &changes {
+            if self.apply(zone, change).is_err() {
+                return Response::new(message, RCODE_SERVFAIL);
+            }
+        }
+
+        Response::new(message, RCODE_NOERROR)
+    }
+
+    fn handle(&self, request: &[u8]) -> Option<Vec<u8>> {
+        self.handle_at(request, unix_time())
+    }
+
+    /// Handles a request received at the unix time `now`.
+    fn handle_at(&self, request: &[u8], now: u64) -> Option<Vec<u8>> {
+        // ignore responses and messages too short for a header
+        if request.len() < 12 || request[2] & 0x80 != 0 {
+            return None;
+        }
+
+        let message = match Message::parse(request) {
+            Ok(message) => message,
+            Err(_) => return Some(Response::header_only(request, RCODE_FORMERR)),
+        };
+
+        let tsig = match Tsig::parse(&message) {
+            Ok(Some(tsig)) => tsig,
+            Ok(None) => {
+                warn!("Refusing an unsigned request");
+                return Some(Response::new(&message, RCODE_REFUSED).encode());
+            }
+            Err(_) => return Some(Response::header_only(request, RCODE_FORMERR)),
+        };
+
+        let (key, digest, secret) = match self.verify(&message, &tsig, now) {
+            Ok(key) => key,
+            Err(error) => {
+                warn!(
+                    "Rejecting a request signed with the key {} (TSIG error {})",
+                    tsig.key_name, error
+                );
+
+                let mut response = Response::new(&message, RCODE_NOTAUTH).encode();
+                let signing = match error {
+                    TSIG_BADTIME => self.find_key(&tsig).ok(),
+                    _ => None,
+                };
+                self.sign(
+                    &mut response,
+                    &tsig,
+                    signing
+                        .as_ref()
+                        .map(|(_, digest, secret)| (*digest, secret.as_slice())),
+                    error,
+                    now,
+                );
+                return Some(response);
+            }
+        };
+
+        let response = match message.opcode {
+            OPCODE_QUERY => self.query(&message, key),
+            OPCODE_UPDATE => self.update(&message, key),
+            _ => Response::new(&message, RCODE_NOTIMP),
+        };
+
+        let mut response = response.encode();
+        self.sign(&mut response, &tsig, Some((digest, &secret)), 0, now);
+
+        Some(response)
+    }
+
+    fn serve_udp(&self, socket: UdpSocket) {
+        let mut buffer = [0; 65535];
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Failed to receive a udp message: {}", e);
+                    continue;
+                }
+            };
+
+            debug!("Received {} bytes from {} via udp", len, peer);
+
+            if let Some(response) = self.handle(&buffer[..len]) {
+                if let Err(e) = socket.send_to(&response, peer) {
+                    warn!("Failed to send a udp response: {}", e);
+                }
+            }
+        }
+    }
+
+    fn serve_tcp_connection(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+        loop {
+            let mut len = [0; 2];
+            if stream.read_exact(&mut len).is_err() {
+                return Ok(());
+            }
+
+            let mut request = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut request)?;
+
+            if let Some(response) = self.handle(&request) {
+                stream.write_all(&(response.len() as u16).to_be_bytes())?;
+                stream.write_all(&response)?;
+            }
+        }
+    }
+}
+
+/// Serves RFC 2136 dynamic updates signed with one of the configured TSIG keys via udp and tcp.
+pub fn serve(config: &Config) -> Result<(), ()> {
+    if config.rfc2136.keys.is_empty() {
+        error!("No TSIG keys configured");
+        return Err(());
+    }
+
+    let listen = &config.rfc2136.listen;
+    let socket =
+        UdpSocket::bind(listen).map_err(|e| error!("Failed to listen on {}: {}", listen, e))?;
+    let listener =
+        TcpListener::bind(listen).map_err(|e| error!("Failed to listen on {}: {}", listen, e))?;
+
+    info!("Listening on {} (udp and tcp)", listen);
+
+    let server = Rfc2136Server {
+        config,
+        sessions: Mutex::new(Sessions::new()),
+    };
+
+    thread::scope(|scope| {
+        scope.spawn(|| server.serve_udp(socket));
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = &server;
+                    scope.spawn(move || {
+                        if let Err(e) = server.serve_tcp_connection(stream) {
+                            debug!("Tcp connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept a tcp connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inwx::Record;
+    use crate::test_util::{self, TempDir};
+    use serde_json::json;
+    use std::fs;
+
+    /// An update of example.com adding a single record with the rdata, followed by `trailing`.
+    fn update_message(record_type: u16, rdata: &[u8], trailing: &[u8]) -> Vec<u8> {
+        let name = b"\x07example\x03com\x00";
+        let mut message = Writer::default();
+
+        message.u16(1);
+        message.u16(u16::from(OPCODE_UPDATE) << 11);
+        for count in &[1, 0, 1, 0] {
+            message.u16(*count);
+        }
+
+        message.bytes.extend_from_slice(name);
+        message.u16(TYPE_SOA);
+        message.u16(CLASS_IN);
+
+        message.bytes.extend_from_slice(name);
+        message.u16(record_type);
+        message.u16(CLASS_IN);
+        message.u32(60);
+        message.u16(rdata.len() as u16);
+        message.bytes.extend_from_slice(rdata);
+        message.bytes.extend_from_slice(trailing);
+
+        message.bytes
+    }
+
+    #[test]
+    fn caa_record() {
+        let bytes = update_message(TYPE_CAA, b"\x00\x05issueletsencrypt.org", &[]);
+        let message = Message::parse(&bytes).unwrap();
+
+        assert_eq!(
+            rdata_to_content(&message, &message.authority[0]).unwrap(),
+            ("0 issue \"letsencrypt.org\"".to_owned(), None)
+        );
+    }
+
+    #[test]
+    fn truncated_caa_record() {
+        // the tag length exceeds the rdata, the bytes after it must not be read
+        let bytes = update_message(TYPE_CAA, b"\x00\x0ai", b"ssue-and-more-bytes");
+        let message = Message::parse(&bytes).unwrap();
+
+        assert!(rdata_to_content(&message, &message.authority[0]).is_err());
+    }
+
+    #[test]
+    fn truncated_txt_record() {
+        let bytes = update_message(TYPE_TXT, b"\x10value", b"-and-more-bytes");
+        let message = Message::parse(&bytes).unwrap();
+
+        assert!(rdata_to_content(&message, &message.authority[0]).is_err());
+    }
+
+    // signed by an independent RFC 8945 implementation (python hmac) with the key update-key,
+    // hmac-sha256, the secret below, time 1700000000 and fudge 300
+    const SECRET: &str = "c2VjcmV0LWtleS1vZi10aGUtdGVzdHMtMDEyMzQ1Njc4OQ==";
+    const TIME: u64 = 1_700_000_000;
+    const SIGNED_UPDATE: &str = "123428000001000000010001076578616d706c6503636f6d00000600010f5f61636d652d6368616c6c656e6765076578616d706c6503636f6d00001000010000003c00060576616c75650a7570646174652d6b65790000fa00ff00000000003d0b686d61632d7368613235360000006553f100012c0020f8b5b1096fc7d190d39e596a8e6d51439aa12b9ddc66b26cc342dfa8aa5b9b36123400000000";
+    const SIGNED_UPDATE_RESPONSE: &str = "1234a8000001000000000001076578616d706c6503636f6d00000600010a7570646174652d6b65790000fa00ff00000000003d0b686d61632d7368613235360000006553f100012c0020844fb0b8f6ebb18f658d4e9969e23fa34c4e05194e27dd5977c8c40a59a912a6123400000000";
+    const BAD_TIME_RESPONSE: &str = "1234a8090001000000000001076578616d706c6503636f6d00000600010a7570646174652d6b65790000fa00ff0000000000430b686d61632d7368613235360000006553f22d012c00208fdb6bdf1c63d4d5325f1b6c798ac8a801eee209e52821d0fd8a26840cef838f12340012000600006553f22d";
+    const SIGNED_QUERY: &str = "432100000001000000000001076578616d706c6503636f6d00000600010a7570646174652d6b65790000fa00ff00000000003d0b686d61632d7368613235360000006553f100012c0020a03b863badd4cbbd040d8677dd0136628ee81f242be31c99dad916b68935097f432100000000";
+    const SIGNED_QUERY_OTHER_ZONE: &str = "432100000001000000000001076578616d706c65036f726700000600010a7570646174652d6b65790000fa00ff00000000003d0b686d61632d7368613235360000006553f100012c00200f22133e980d9e3950e44edc555f274f2ce1d55da40f4ab39ed79e9e05a917ad432100000000";
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// A server for example.com and example.org with the records in a file, the key may only
+    /// change example.com.
+    fn server(name: &str, key_name: &str) -> (Rfc2136Server<'static>, TempDir) {
+        let directory = TempDir::new(&format!("rfc2136-{}", name));
+
+        let config = test_util::config(json!({
+            "rfc2136": {
+                "keys": [{ "name": key_name, "secret": SECRET, "zones": ["example.com"] }],
+            },
+            "records_file": {
+                "path": directory.join("records.json"),
+                "zones": ["example.com", "example.org"],
+            },
+        }));
+
+        let server = Rfc2136Server {
+            config,
+            sessions: Mutex::new(Sessions::new()),
+        };
+
+        (server, directory)
+    }
+
+    /// Returns the rcode and the TSIG error of a response.
+    fn result(response: &[u8]) -> (u8, u16) {
+        let message = Message::parse(response).unwrap();
+        let mut rdata = message.rdata(message.additional.last().unwrap());
+        rdata.name().unwrap();
+        rdata.bytes(8).unwrap();
+        let mac_len = rdata.u16().unwrap() as usize;
+        rdata.bytes(mac_len + 2).unwrap();
+
+        (response[3] & 0x0f, rdata.u16().unwrap())
+    }
+
+    fn records(server: &Rfc2136Server) -> Vec<Record> {
+        let path = &server.config.records_file.as_ref().unwrap().path;
+
+        match fs::read(path) {
+            Ok(records) => serde_json::from_slice(&records).unwrap(),
+            Err(_) => vec![],
+        }
+    }
+
+    #[test]
+    fn signed_update() {
+        let (server, _directory) = server("signed-update", "update-key");
+        let response = server.handle_at(&hex(SIGNED_UPDATE), TIME).unwrap();
+
+        // verified with the mac of the request by the same implementation
+        assert_eq!(response, hex(SIGNED_UPDATE_RESPONSE));
+        assert_eq!(result(&response), (RCODE_NOERROR, 0));
+
+        let records = records(&server);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "_acme-challenge.example.com");
+        assert_eq!(
+            (records[0].content.as_str(), records[0].ttl),
+            ("value", 300)
+        );
+    }
+
+    #[test]
+    fn bad_mac() {
+        let (server, _directory) = server("bad-mac", "update-key");
+        let mut request = hex(SIGNED_UPDATE);
+        let mac_end = request.len() - 6;
+        request[mac_end - 1] ^= 1;
+
+        let response = server.handle_at(&request, TIME).unwrap();
+
+        assert_eq!(result(&response), (RCODE_NOTAUTH, TSIG_BADSIG));
+        assert!(records(&server).is_empty());
+    }
+
+    #[test]
+    fn bad_time() {
+        let (server, _directory) = server("bad-time", "update-key");
+
+        assert_eq!(
+            result(&server.handle_at(&hex(SIGNED_UPDATE), TIME + 300).unwrap()).1,
+            0
+        );
+
+        // the error response is signed and contains the time of the server
+        let response = server.handle_at(&hex(SIGNED_UPDATE), TIME + 301).unwrap();
+        assert_eq!(response, hex(BAD_TIME_RESPONSE));
+        assert_eq!(result(&response), (RCODE_NOTAUTH, TSIG_BADTIME));
+
+        let response = server.handle_at(&hex(SIGNED_UPDATE), TIME - 301).unwrap();
+        assert_eq!(result(&response), (RCODE_NOTAUTH, TSIG_BADTIME));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let (server, _directory) = server("unknown-key", "other-key");
+        let response = server.handle_at(&hex(SIGNED_UPDATE), TIME).unwrap();
+
+        assert_eq!(result(&response), (RCODE_NOTAUTH, TSIG_BADKEY));
+        assert!(records(&server).is_empty());
+    }
+
+    #[test]
+    fn query_scope() {
+        let (server, _directory) = server("query-scope", "update-key");
+
+        let response = server.handle_at(&hex(SIGNED_QUERY), TIME).unwrap();
+        assert_eq!(result(&response), (RCODE_NOERROR, 0));
+        assert_eq!(Message::parse(&response).unwrap().answers.len(), 1);
+
+        // example.org is hosted as well, but not by the zones of the key
+        let response = server
+            .handle_at(&hex(SIGNED_QUERY_OTHER_ZONE), TIME)
+            .unwrap();
+        assert_eq!(result(&response), (RCODE_REFUSED, 0));
+    }
+}
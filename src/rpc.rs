@@ -1,38 +1,69 @@
+use bytes::Bytes;
 use cookie::{Cookie, CookieJar};
-use reqwest;
 use reqwest::{Client, Response, StatusCode};
 use std::fmt;
 use sxd_document::dom::Document;
 use sxd_document::writer::format_document;
 use sxd_document::{parser, Package};
 use sxd_xpath::evaluate_xpath;
+use zeroize::{Zeroize, Zeroizing};
 
+/// A failed inwx api call, the http client used for it is an implementation detail.
 #[derive(Debug)]
-pub enum RpcError {
+pub struct RpcError(pub(crate) RpcErrorKind);
+
+#[derive(Debug)]
+pub(crate) enum RpcErrorKind {
     ConnectionError(reqwest::Error),
     InvalidResponse,
     ApiError {
         method: String,
+        code: i32,
         reason: String,
         msg: String,
     },
 }
 
+impl RpcError {
+    /// Returns the code of the error if the inwx api returned one.
+    pub fn code(&self) -> Option<i32> {
+        match self.0 {
+            RpcErrorKind::ApiError { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl From<RpcErrorKind> for RpcError {
+    fn from(kind: RpcErrorKind) -> RpcError {
+        RpcError(kind)
+    }
+}
+
+impl From<reqwest::Error> for RpcError {
+    fn from(error: reqwest::Error) -> RpcError {
+        RpcError(RpcErrorKind::ConnectionError(error))
+    }
+}
+
 impl fmt::Display for RpcError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &RpcError::InvalidResponse => write!(f, "The inwx api did not return a valid response"),
-            &RpcError::ConnectionError(ref e) => {
+        match &self.0 {
+            RpcErrorKind::InvalidResponse => {
+                write!(f, "The inwx api did not return a valid response")
+            }
+            RpcErrorKind::ConnectionError(e) => {
                 write!(f, "Could not connect to the inwx api: {}", e)
             }
-            &RpcError::ApiError {
-                ref method,
-                ref msg,
-                ref reason,
+            RpcErrorKind::ApiError {
+                method,
+                code,
+                msg,
+                reason,
             } => write!(
                 f,
-                "The inwx api did return an error: method={}, msg={}, reason={}",
-                method, msg, reason
+                "The inwx api did return an error: method={}, code={}, msg={}, reason={}",
+                method, code, msg, reason
             ),
         }
     }
@@ -48,13 +79,35 @@ pub enum RpcRequestParameterValue {
     Int(i32),
 }
 
+// the values may be credentials, i.e. the password of account.login
+impl Drop for RpcRequestParameterValue {
+    fn drop(&mut self) {
+        if let RpcRequestParameterValue::String(ref mut value) = self {
+            value.zeroize();
+        }
+    }
+}
+
+// parameters whose values must not show up in the logs
+const SECRET_PARAMETERS: &[&str] = &["pass"];
+
+#[derive(Clone)]
 pub struct RpcRequest {
-    body: Vec<u8>,
+    body: Zeroizing<Vec<u8>>,
+    log_body: Vec<u8>,
     method: String,
 }
 
 impl RpcRequest {
     pub fn new(method: &str, parameters: &[RpcRequestParameter]) -> RpcRequest {
+        RpcRequest {
+            body: Zeroizing::new(Self::format_body(method, parameters, false)),
+            log_body: Self::format_body(method, parameters, true),
+            method: method.to_owned(),
+        }
+    }
+
+    fn format_body(method: &str, parameters: &[RpcRequestParameter], redact: bool) -> Vec<u8> {
         let package = Package::new();
         let doc = package.as_document();
 
@@ -78,13 +131,19 @@ impl RpcRequest {
             let member = doc.create_element("member");
 
             let name = doc.create_element("name");
-            name.append_child(doc.create_text(&param.name));
+            name.append_child(doc.create_text(param.name));
             member.append_child(name);
 
             let value = doc.create_element("value");
             member.append_child(value);
             match param.value {
                 RpcRequestParameterValue::String(ref val) => {
+                    let val = if redact && SECRET_PARAMETERS.contains(&param.name) {
+                        "***"
+                    } else {
+                        val
+                    };
+
                     let string = doc.create_element("string");
                     string.append_child(doc.create_text(val));
                     value.append_child(string);
@@ -102,34 +161,35 @@ impl RpcRequest {
         let mut body = Vec::new();
         format_document(&doc, &mut body).unwrap();
 
-        RpcRequest {
-            body: body,
-            method: method.to_owned(),
-        }
+        body
     }
 
-    pub fn send(self, url: &str, cookies: &mut CookieJar) -> Result<RpcResponse, RpcError> {
-        let client = Client::new();
-
-        if let Ok(body) = std::str::from_utf8(&self.body) {
+    pub async fn send(
+        self,
+        client: &Client,
+        url: &str,
+        cookies: &mut CookieJar,
+    ) -> Result<RpcResponse, RpcError> {
+        if let Ok(body) = std::str::from_utf8(&self.log_body) {
             trace!("Sending request {}", body);
         }
 
-        let mut request = client.post(url).body(self.body);
+        // the body is zeroized once the client has sent it and drops it
+        let mut request = client.post(url).body(Bytes::from_owner(self.body));
 
         let cookie_values: Vec<String> = cookies
             .iter()
             .map(|cookie| format!("{}", cookie.encoded()))
             .collect();
 
-        if cookie_values.len() > 0 {
+        if !cookie_values.is_empty() {
             let cookie_values = cookie_values.join(";");
             request = request.header(reqwest::header::COOKIE, cookie_values);
         }
 
-        let response = request.send().map_err(|e| RpcError::ConnectionError(e))?;
+        let response = request.send().await?;
 
-        RpcResponse::new(response, self.method, cookies)
+        RpcResponse::new(response, self.method, cookies).await
     }
 }
 
@@ -138,28 +198,36 @@ pub struct RpcResponse {
 }
 
 impl RpcResponse {
-    fn new(
-        mut response: Response,
+    async fn new(
+        response: Response,
         method: String,
         cookies: &mut CookieJar,
     ) -> Result<RpcResponse, RpcError> {
         if response.status() == StatusCode::OK {
-            if let Ok(ref response_text) = response.text() {
-                trace!("Received response {:?}", response_text);
+            for header in response.headers().get_all(reqwest::header::SET_COOKIE) {
+                if let Ok(value) = header.to_str() {
+                    if let Ok(cookie) = Cookie::parse(value.to_owned()) {
+                        cookies.add(cookie);
+                    }
+                }
+            }
+
+            if let Ok(ref response_text) = response.text().await {
+                // the login response contains the session data
+                if method == "account.login" {
+                    trace!("Received response to {}", method);
+                } else {
+                    trace!("Received response {:?}", response_text);
+                }
 
                 if let Ok(package) = parser::parse(response_text) {
                     let mut success = false;
+                    let mut code = 0;
 
-                    for header in response.headers().get_all(reqwest::header::SET_COOKIE) {
-                        if let Ok(value) = header.to_str() {
-                            if let Ok(cookie) = Cookie::parse(value.to_owned()) {
-                                cookies.add(cookie);
-                            }
-                        }
-                    }
+                    if let Ok(value) = evaluate_xpath(&package.as_document(), "/methodResponse/params/param/value/struct/member[name/text()=\"code\"]/value/int/text()") {
+                        if let Ok(value) = value.string().parse::<i32>() {
+                            code = value;
 
-                    if let Ok(code) = evaluate_xpath(&package.as_document(), "/methodResponse/params/param/value/struct/member[name/text()=\"code\"]/value/int/text()") {
-                        if let Ok(code) = code.string().parse::<i32>() {
                             if code < 2000 {
                                 success = true;
                             }
@@ -184,11 +252,13 @@ impl RpcResponse {
                             reason = value.string();
                         }
 
-                        return Err(RpcError::ApiError {
+                        return Err(RpcErrorKind::ApiError {
                             method,
+                            code,
                             msg,
                             reason,
-                        });
+                        }
+                        .into());
                     }
 
                     return Ok(RpcResponse { package });
@@ -196,10 +266,40 @@ impl RpcResponse {
             }
         }
 
-        Err(RpcError::InvalidResponse)
+        Err(RpcErrorKind::InvalidResponse.into())
     }
 
-    pub fn get_document(&self) -> Document {
+    pub fn get_document(&self) -> Document<'_> {
         self.package.as_document()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted_log_body() {
+        let request = RpcRequest::new(
+            "account.login",
+            &[
+                RpcRequestParameter {
+                    name: "user",
+                    value: RpcRequestParameterValue::String("user".to_owned()),
+                },
+                RpcRequestParameter {
+                    name: "pass",
+                    value: RpcRequestParameterValue::String("password".to_owned()),
+                },
+            ],
+        );
+
+        let body = String::from_utf8(request.body.to_vec()).unwrap();
+        let log_body = String::from_utf8(request.log_body.clone()).unwrap();
+
+        assert!(body.contains("<string>password</string>"));
+        assert!(!log_body.contains("password"));
+        assert!(log_body.contains("<string>***</string>"));
+        assert!(log_body.contains("<string>user</string>"));
+    }
+}
@@ -0,0 +1,63 @@
+//! The fixtures shared by the tests of the modules.
+
+use crate::config::Config;
+use reqwest::blocking::Client;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use tiny_http::Server;
+
+/// A directory of a test, it is removed when it is dropped, so a failed test cleans up as well.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("letsencrypt-inwx-{}-{}", name, process::id()));
+
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Parses the config and leaks it, so servers running on other threads can borrow it.
+pub fn config(value: serde_json::Value) -> &'static Config {
+    Box::leak(Box::new(serde_json::from_value(value).unwrap()))
+}
+
+/// Runs `handle` with a http server on an ephemeral port in another thread and returns the url.
+pub fn spawn_server<F>(handle: F) -> String
+where
+    F: FnOnce(Server) + Send + 'static,
+{
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+
+    thread::spawn(move || handle(server));
+
+    url
+}
+
+/// A http client for the servers of the tests, which ignores the proxies of the environment.
+pub fn client() -> Client {
+    Client::builder().no_proxy().build().unwrap()
+}